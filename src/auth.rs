@@ -0,0 +1,350 @@
+//! Credentials for private binary caches.
+//!
+//! Credentials can be configured in two ways:
+//!
+//! * in a netrc file, like the one that Nix reads (the `netrc-file` option of `nix.conf`).
+//!   Requests to the hosts listed in the file use HTTP basic authentication.
+//! * per binary cache, as part of the binary cache URL (see `hydra::BinaryCache`): either a
+//!   login and password in the URL itself, or a bearer token that is read from a file or an
+//!   environment variable. Requests to S3 buckets are signed with AWS credentials (see `s3`).
+//!
+//! Credentials are only ever sent in the `Authorization` header of requests. They are removed
+//! from binary cache URLs when these are parsed, so they do not show up in error messages, the
+//! database or the response cache. The `Debug` output of `Credentials` hides the secrets as well.
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+use hyper::header::{self, Authorization, Headers};
+use hyper::Uri;
+
+use s3::{self, AwsCredentials};
+
+error_chain! {
+    errors {
+        ReadNetrc(path: PathBuf) {
+            description("netrc read error")
+            display("reading the netrc file '{}' failed", path.to_string_lossy())
+        }
+        NetrcSyntax(message: String) {
+            description("netrc syntax error")
+            display("invalid netrc file: {}", message)
+        }
+        ReadToken(source: String) {
+            description("bearer token read error")
+            display("reading the bearer token from {} failed", source)
+        }
+        EmptyToken(source: String) {
+            description("empty bearer token")
+            display("the bearer token from {} is empty", source)
+        }
+    }
+
+    links {
+        S3(s3::Error, s3::ErrorKind);
+    }
+
+    foreign_links {
+        Io(io::Error);
+    }
+}
+
+/// Credentials for requests to a binary cache.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// HTTP basic authentication with a login and password.
+    Basic { login: String, password: String },
+    /// A bearer token, as used by attic and similar binary cache servers.
+    Bearer(String),
+    /// AWS credentials for signing requests to an S3 bucket in the given region.
+    S3 { credentials: AwsCredentials, region: String },
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Credentials::Basic { ref login, .. } => {
+                write!(f, "Basic {{ login: {:?}, password: *** }}", login)
+            }
+            Credentials::Bearer(_) => write!(f, "Bearer(***)"),
+            Credentials::S3 { ref credentials, ref region } => {
+                write!(f, "S3 {{ credentials: {:?}, region: {:?} }}", credentials, region)
+            }
+        }
+    }
+}
+
+impl Credentials {
+    /// Returns whether a request with these credentials contains the secret itself, so that it
+    /// can be read by anyone who can see the request if it is not encrypted.
+    ///
+    /// This is true for all credentials except for S3 credentials without a session token, since
+    /// S3 requests are only signed with the secret access key (see `AwsCredentials::is_sent_in_clear`).
+    pub fn is_sent_in_clear(&self) -> bool {
+        match *self {
+            Credentials::Basic { .. } | Credentials::Bearer(_) => true,
+            Credentials::S3 { ref credentials, .. } => credentials.is_sent_in_clear(),
+        }
+    }
+
+    /// Adds the `Authorization` header for these credentials to the headers of a GET request to
+    /// the given URI.
+    pub fn authorize(&self, uri: &Uri, headers: &mut Headers) {
+        match *self {
+            Credentials::Basic { ref login, ref password } => headers.set(Authorization(header::Basic {
+                username: login.clone(),
+                password: Some(password.clone()),
+            })),
+            Credentials::Bearer(ref token) => headers.set(Authorization(header::Bearer {
+                token: token.clone(),
+            })),
+            Credentials::S3 { ref credentials, ref region } => {
+                for (name, value) in s3::sign(credentials, region, uri, SystemTime::now()) {
+                    headers.set_raw(name, value);
+                }
+            }
+        }
+    }
+}
+
+/// Where the credentials for a binary cache come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CredentialSource {
+    /// Credentials that are known already, such as a login and password from a URL.
+    Credentials(Credentials),
+    /// A bearer token that is read from a file. Leading and trailing whitespace is ignored.
+    TokenFile(PathBuf),
+    /// A bearer token that is read from an environment variable.
+    TokenEnv(String),
+    /// AWS credentials for an S3 bucket, read from the environment or the given profile.
+    S3 { bucket: String, region: String, profile: Option<String> },
+}
+
+impl CredentialSource {
+    /// Returns the credentials, reading them from their source if necessary.
+    pub fn resolve(&self) -> Result<Credentials> {
+        let (source, token) = match *self {
+            CredentialSource::Credentials(ref credentials) => return Ok(credentials.clone()),
+            CredentialSource::S3 { ref bucket, ref region, ref profile } => {
+                return Ok(Credentials::S3 {
+                    credentials: AwsCredentials::load(bucket, profile.as_ref().map(|p| p.as_str()))?,
+                    region: region.clone(),
+                });
+            }
+            CredentialSource::TokenFile(ref path) => {
+                let source = format!("the file '{}'", path.to_string_lossy());
+                let mut token = String::new();
+                File::open(path)
+                    .and_then(|mut file| file.read_to_string(&mut token))
+                    .chain_err(|| ErrorKind::ReadToken(source.clone()))?;
+                (source, token)
+            }
+            CredentialSource::TokenEnv(ref var) => {
+                let source = format!("the environment variable '{}'", var);
+                let token = env::var(var).chain_err(|| ErrorKind::ReadToken(source.clone()))?;
+                (source, token)
+            }
+        };
+
+        let token = token.trim();
+        if token.is_empty() {
+            bail!(ErrorKind::EmptyToken(source));
+        }
+        Ok(Credentials::Bearer(token.to_string()))
+    }
+}
+
+/// The credentials from a netrc file.
+///
+/// The format is the same that curl and Nix use: a sequence of whitespace-separated tokens, where
+/// `machine HOST` starts the entry for a host and `default` starts the entry for all other hosts.
+/// An entry is followed by `login LOGIN` and `password PASSWORD`. Values that contain whitespace
+/// can be enclosed in double quotes. `account` values and macros (`macdef`) are ignored, as are
+/// lines starting with `#`.
+///
+/// # Example
+///
+/// ```
+/// use nix_index::auth::{Credentials, Netrc};
+///
+/// let netrc: Netrc = "
+///     ## our private cache
+///     machine cache.example.org login alice password \"correct horse\"
+///     default login anonymous password guest
+/// ".parse().unwrap();
+///
+/// let alice = Credentials::Basic { login: "alice".to_string(), password: "correct horse".to_string() };
+/// assert_eq!(netrc.credentials("cache.example.org"), Some(&alice));
+/// let anonymous = Credentials::Basic { login: "anonymous".to_string(), password: "guest".to_string() };
+/// assert_eq!(netrc.credentials("cache.nixos.org"), Some(&anonymous));
+///
+/// // the secrets are not part of the debug output
+/// assert_eq!(format!("{:?}", alice), "Basic { login: \"alice\", password: *** }");
+///
+/// assert!("machine cache.example.org login".parse::<Netrc>().is_err());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Netrc {
+    machines: Vec<(String, Credentials)>,
+    default: Option<Credentials>,
+}
+
+/// An entry of a netrc file while it is being parsed.
+#[derive(Default)]
+struct NetrcEntry {
+    /// The host of the entry, or `None` for the default entry.
+    host: Option<String>,
+    login: Option<String>,
+    password: Option<String>,
+}
+
+impl Netrc {
+    /// Reads and parses the netrc file at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Netrc> {
+        let path = path.as_ref();
+        let read = || -> Result<Netrc> {
+            let mut contents = String::new();
+            File::open(path)?.read_to_string(&mut contents)?;
+            contents.parse()
+        };
+        read().chain_err(|| ErrorKind::ReadNetrc(path.to_path_buf()))
+    }
+
+    /// Returns the credentials for the given host, if there are any.
+    ///
+    /// If there is no entry for the host, the credentials from the default entry are returned.
+    pub fn credentials(&self, host: &str) -> Option<&Credentials> {
+        self.machines
+            .iter()
+            .find(|(machine, _)| machine.eq_ignore_ascii_case(host))
+            .map(|(_, credentials)| credentials)
+            .or(self.default.as_ref())
+    }
+
+    /// Adds a completely parsed entry.
+    fn add(&mut self, entry: NetrcEntry) {
+        if entry.login.is_none() && entry.password.is_none() {
+            return;
+        }
+        let credentials = Credentials::Basic {
+            login: entry.login.unwrap_or_default(),
+            password: entry.password.unwrap_or_default(),
+        };
+        match entry.host {
+            Some(host) => self.machines.push((host, credentials)),
+            // like curl, we use the first default entry
+            None => {
+                if self.default.is_none() {
+                    self.default = Some(credentials);
+                }
+            }
+        }
+    }
+}
+
+/// Splits a line of a netrc file into tokens, handling quoted tokens.
+fn netrc_tokens(line: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        let quoted = match chars.peek() {
+            Some(&c) => c == '"',
+            None => break,
+        };
+
+        let mut token = String::new();
+        if quoted {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c) => token.push(c),
+                        None => bail!(ErrorKind::NetrcSyntax("unterminated quoted string".to_string())),
+                    },
+                    Some(c) => token.push(c),
+                    None => bail!(ErrorKind::NetrcSyntax("unterminated quoted string".to_string())),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+        }
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+impl FromStr for Netrc {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Netrc> {
+        // collect the tokens of all lines, skipping comments and the bodies of macros, which
+        // extend until the next empty line
+        let mut tokens = Vec::new();
+        let mut in_macro = false;
+        for line in s.lines() {
+            if in_macro {
+                in_macro = !line.trim().is_empty();
+                continue;
+            }
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            let line_tokens = netrc_tokens(line)?;
+            in_macro = line_tokens.iter().any(|t| t == "macdef");
+            tokens.extend(line_tokens);
+        }
+
+        let mut netrc = Netrc::default();
+        let mut entry: Option<NetrcEntry> = None;
+        let mut tokens = tokens.into_iter();
+        while let Some(token) = tokens.next() {
+            let mut value = || {
+                tokens.next().ok_or_else(|| ErrorKind::NetrcSyntax(format!("missing value after '{}'", token)))
+            };
+            match token.as_str() {
+                "machine" => {
+                    let host = value()?;
+                    netrc.add(entry.take().unwrap_or_default());
+                    entry = Some(NetrcEntry { host: Some(host), ..NetrcEntry::default() });
+                }
+                "default" => {
+                    netrc.add(entry.take().unwrap_or_default());
+                    entry = Some(NetrcEntry::default());
+                }
+                "macdef" => {
+                    value()?;
+                }
+                "login" | "password" | "account" => {
+                    let value = value()?;
+                    let current = entry.as_mut().ok_or_else(|| {
+                        ErrorKind::NetrcSyntax(format!("'{}' must follow 'machine' or 'default'", token))
+                    })?;
+                    match token.as_str() {
+                        "login" => current.login = Some(value),
+                        "password" => current.password = Some(value),
+                        _ => {}
+                    }
+                }
+                other => bail!(ErrorKind::NetrcSyntax(format!("unknown keyword '{}'", other))),
+            }
+        }
+        if let Some(entry) = entry {
+            netrc.add(entry);
+        }
+
+        Ok(netrc)
+    }
+}
@@ -17,14 +17,15 @@ use std::path::PathBuf;
 use std::result;
 use std::process;
 use std::str;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use separator::Separatable;
 use clap::{Arg, App, ArgMatches};
 use regex::bytes::Regex;
 use ansi_term::Colour::Red;
 
 use nix_index::database;
-use nix_index::files::{self, FileType, FileTreeEntry};
+use nix_index::files::{self, FileNode, FileTree, FileType, FileTreeEntry};
+use nix_index::package::StorePath;
 
 error_chain! {
     errors {
@@ -38,9 +39,21 @@ error_chain! {
             description("grep builder error")
             display("constructing the regular expression from the pattern '{}' failed.", pattern)
         }
+        NoSuchPackage(package: String) {
+            description("package not found")
+            display("no package matching '{}' was found in the database.", package)
+        }
     }
 }
 
+/// Selects the package(s) whose files should be listed, for the `--list` and `--list-hash` options.
+enum ListQuery {
+    /// List the files of the packages for an attribute (with optional output).
+    Attr(String),
+    /// List the files of the package with the given hash.
+    Hash(String),
+}
+
 /// The struct holding the parsed arguments for searching
 struct Args {
     /// Path of the nix-index database.
@@ -54,6 +67,10 @@ struct Args {
     only_toplevel: bool,
     color: bool,
     minimal: bool,
+    /// If set, list the files of the selected packages instead of searching.
+    list: Option<ListQuery>,
+    /// Render listings as a tree.
+    tree: bool,
 }
 
 /// The main function of this module: searches with the given options in the database.
@@ -124,6 +141,11 @@ fn locate(args: &Args) -> Result<()> {
                 println!("{}", attr);
             }
         } else {
+            // paths indexed with `--unsigned mark` that had no valid signature
+            if store_path.verified() == Some(false) {
+                attr = format!("{} [unverified]", attr);
+            }
+
             print!(
                 "{:<40} {:>14} {:>1} {}",
                 attr,
@@ -160,13 +182,108 @@ fn locate(args: &Args) -> Result<()> {
     Ok(())
 }
 
+/// Prints a file tree with box drawing characters, similar to the `tree` tool.
+///
+/// `prefix` is printed before every line except the first and grows with the depth of the tree.
+fn print_tree(name: &str, tree: &FileTree, prefix: &str) {
+    match *tree.node() {
+        FileNode::Symlink { ref target } => {
+            println!("{} -> {}", name, String::from_utf8_lossy(target));
+        }
+        FileNode::Regular { .. } => println!("{}", name),
+        FileNode::Directory { ref contents, .. } => {
+            println!("{}", name);
+            let mut entries = contents.iter().collect::<Vec<_>>();
+            entries.sort_by(|a, b| Ord::cmp(a.0, b.0));
+
+            let count = entries.len();
+            for (i, (child, subtree)) in entries.into_iter().enumerate() {
+                let last = i + 1 == count;
+                let (branch, indent) = if last { ("└── ", "    ") } else { ("├── ", "│   ") };
+                print!("{}{}", prefix, branch);
+                print_tree(
+                    &String::from_utf8_lossy(child),
+                    subtree,
+                    &format!("{}{}", prefix, indent),
+                );
+            }
+        }
+    }
+}
+
+/// Removes all entries from a file tree whose type is not one of `types`, keeping the directories
+/// that contain matching entries so that the structure of the tree stays intact.
+///
+/// Returns `None` if nothing in the tree matches.
+fn filter_tree(tree: &FileTree, types: &[FileType]) -> Option<FileTree> {
+    match *tree.node() {
+        FileNode::Directory { ref contents, .. } => {
+            let contents = contents
+                .iter()
+                .filter_map(|(name, subtree)| filter_tree(subtree, types).map(|subtree| (name.clone(), subtree)))
+                .collect::<HashMap<_, _>>();
+            if contents.is_empty() && !types.contains(&FileType::Directory) {
+                return None;
+            }
+            Some(FileTree::directory(contents))
+        }
+        ref node if types.contains(&node.get_type()) => Some(tree.clone()),
+        _ => None,
+    }
+}
+
+/// Lists all files of the packages selected by `query`, as requested by `--list` or `--list-hash`.
+fn list(args: &Args, query: &ListQuery) -> Result<()> {
+    let index_file = args.database.join("files");
+    let db = database::Reader::open(&index_file).chain_err(|| {
+        ErrorKind::ReadDatabase(index_file.clone())
+    })?;
+
+    let packages = db.packages().toplevel(args.only_toplevel);
+    let (packages, requested) = match *query {
+        ListQuery::Attr(ref attr) => (packages.attr(Some(attr.clone())), attr),
+        ListQuery::Hash(ref hash) => (packages.hash(Some(hash.clone())), hash),
+    };
+
+    let mut found = false;
+    for package in packages {
+        let (store_path, mut entries): (StorePath, _) =
+            package.chain_err(|| ErrorKind::ReadDatabase(index_file.clone()))?;
+        found = true;
+
+        if args.tree {
+            let tree = FileTree::from_list(entries)
+                .ok_or_else(|| ErrorKind::ReadDatabase(index_file.clone()))?;
+            if let Some(tree) = filter_tree(&tree, &args.file_type) {
+                print_tree(&store_path.as_str(), &tree, "");
+            }
+            continue;
+        }
+
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        for FileTreeEntry { path, node } in entries {
+            if !args.file_type.iter().any(|t| &node.get_type() == t) {
+                continue;
+            }
+            println!("{}{}", store_path.as_str(), String::from_utf8_lossy(&path));
+        }
+    }
+
+    if !found {
+        bail!(ErrorKind::NoSuchPackage(requested.clone()));
+    }
+
+    Ok(())
+}
+
 /// Extract the parsed arguments for clap's arg matches.
 ///
 /// Handles parsing the values of more complex arguments.
 fn process_args(matches: &ArgMatches) -> result::Result<Args, clap::Error> {
+    // the pattern is not required if we only list the files of a package
     let pattern_arg = matches
         .value_of("PATTERN")
-        .expect("pattern arg required")
+        .unwrap_or("")
         .to_string();
     let package_arg = matches.value_of("package");
     let start_anchor = if matches.is_present("at-root") {
@@ -217,6 +334,11 @@ fn process_args(matches: &ArgMatches) -> result::Result<Args, clap::Error> {
         only_toplevel: matches.is_present("toplevel"),
         color: color.unwrap_or_else(isatty::stdout_isatty),
         minimal: matches.is_present("minimal"),
+        list: matches
+            .value_of("list")
+            .map(|attr| ListQuery::Attr(attr.trim_start_matches("nixpkgs.").to_string()))
+            .or_else(|| matches.value_of("list-hash").map(|hash| ListQuery::Hash(hash.to_string()))),
+        tree: matches.is_present("tree"),
     };
     Ok(args)
 }
@@ -231,6 +353,11 @@ a file matching that path:
 $ nix-locate 'bin/firefox'
 ...all packages containing a file named 'bin/firefox'
 
+To list all files of a package instead, use `--list` with the attribute of the package:
+
+$ nix-locate --list hello
+...all files of the package for the attribute 'hello'
+
 Before using this tool, you first need to generate a nix-index database.
 Use the `nix-index` tool to do that.
 
@@ -266,7 +393,7 @@ fn main() {
              .default_value(&cache_dir)
              .help("Directory where the index is stored"))
         .arg(Arg::with_name("PATTERN")
-             .required(true)
+             .required_unless_one(&["list", "list-hash"])
              .help("Pattern for which to search")
              .index(1))
         .arg(Arg::with_name("regex")
@@ -329,13 +456,35 @@ fn main() {
                     Other details such as size or store path are omitted.\n\
                     This is useful for scripts that use the output of nix-locate."
              ))
+        .arg(Arg::with_name("list")
+             .long("list")
+             .value_name("ATTR")
+             .conflicts_with_all(&["PATTERN", "list-hash"])
+             .help("List all files of the package(s) for the attribute ATTR instead of searching.\n\
+                    ATTR may include the output, for example `coreutils.out`. A leading `nixpkgs.` is ignored.\n\
+                    This includes the dependencies of the package, unless --top-level is given. Store paths that were \
+                    indexed with --local or --paths-from are listed by their name, like `hello-2.10`."))
+        .arg(Arg::with_name("list-hash")
+             .long("list-hash")
+             .value_name("HASH")
+             .conflicts_with("PATTERN")
+             .help("List all files of the package with the given store path HASH instead of searching."))
+        .arg(Arg::with_name("tree")
+             .long("tree")
+             .help("When listing files with --list or --list-hash, render the files as a tree.\n\
+                    With --type, only the files of the given types are shown, together with the directories that contain them."))
         .after_help(LONG_USAGE)
         .get_matches();
 
 
     let args = process_args(&matches).unwrap_or_else(|e| e.exit());
 
-    if let Err(e) = locate(&args) {
+    let result = match args.list {
+        Some(ref query) => list(&args, query),
+        None => locate(&args),
+    };
+
+    if let Err(e) = result {
         errln!("error: {}", e);
 
         for e in e.iter().skip(1) {
@@ -0,0 +1,257 @@
+//! A persistent on-disk cache for responses from binary caches.
+//!
+//! Store paths are immutable, so the narinfo and file listing of a store path never change once
+//! they have been published. Caching them on disk means that repeated runs of `nix-index` only
+//! need to download the files for store paths that are new since the last run.
+//!
+//! Each response is stored in a separate file, named after the SHA-256 hash of its URL. The first
+//! byte of the file tells whether the response was found (`+`) or not (`-`). Responses that were
+//! found are followed by the name of their content encoding, a newline and the body as it was
+//! received, so that the cache stays small. Files that were not found are only cached for a limited
+//! time, since they may be uploaded to the binary cache later (for example, when Hydra finishes a build).
+//!
+//! Reading an entry updates its modification time, and the cache is pruned while it is written to,
+//! so that it never grows much larger than its maximum size and the least recently used entries
+//! are removed first.
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use ring::digest::{self, SHA256};
+
+/// The first byte of entries for responses that were found.
+const FOUND: u8 = b'+';
+
+/// The first byte (and only content) of entries for responses that were not found.
+const NOT_FOUND: u8 = b'-';
+
+/// The default time for which responses that were not found are cached.
+const DEFAULT_NEGATIVE_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// The default maximum size of the cache.
+const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// The cache is pruned whenever this fraction of its maximum size has been written to it.
+const PRUNE_FRACTION: u64 = 16;
+
+/// An on-disk cache of responses, keyed by their URL.
+///
+/// # Example
+///
+/// ```
+/// use std::env;
+/// use std::time::Duration;
+/// use nix_index::cache::ResponseCache;
+///
+/// let dir = env::temp_dir().join(format!("nix-index-cache-doctest-{}", std::process::id()));
+/// let cache = ResponseCache::new(&dir);
+///
+/// assert_eq!(cache.get("http://example.org/a.narinfo"), None);
+/// cache.put("http://example.org/a.narinfo", Some(("identity", b"StorePath: ..."))).unwrap();
+/// cache.put("http://example.org/b.narinfo", None).unwrap();
+/// cache.put("http://example.org/c.ls", Some(("br", b"+"))).unwrap();
+/// assert_eq!(
+///     cache.get("http://example.org/a.narinfo"),
+///     Some(Some(("identity".to_string(), b"StorePath: ...".to_vec())))
+/// );
+/// assert_eq!(cache.get("http://example.org/b.narinfo"), Some(None));
+/// assert_eq!(cache.get("http://example.org/c.ls"), Some(Some(("br".to_string(), b"+".to_vec()))));
+///
+/// // with a TTL of zero, responses that were not found expire immediately
+/// let cache = cache.negative_ttl(Duration::from_secs(0));
+/// std::thread::sleep(Duration::from_millis(10));
+/// assert_eq!(cache.get("http://example.org/b.narinfo"), None);
+/// // only the expired negative entry is removed, even though the body of `c.ls` is just one byte
+/// assert_eq!(cache.prune().unwrap(), 1);
+/// assert!(cache.get("http://example.org/c.ls").is_some());
+///
+/// // shrinking the cache removes the least recently used entries
+/// let cache = cache.max_size(25);
+/// std::thread::sleep(Duration::from_millis(10));
+/// cache.get("http://example.org/a.narinfo");
+/// assert_eq!(cache.prune().unwrap(), 5);
+/// assert_eq!(cache.get("http://example.org/c.ls"), None);
+/// assert!(cache.get("http://example.org/a.narinfo").is_some());
+///
+/// // writing to a full cache prunes it
+/// cache.put("http://example.org/d.ls", Some(("br", b"..."))).unwrap();
+/// assert_eq!(cache.get("http://example.org/a.narinfo"), None);
+/// assert!(cache.get("http://example.org/d.ls").is_some());
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    dir: PathBuf,
+    negative_ttl: Duration,
+    max_size: u64,
+    /// The number of bytes written since the cache was last pruned, shared by all clones.
+    written: Arc<AtomicU64>,
+}
+
+impl ResponseCache {
+    /// Creates a cache that stores its entries in the given directory.
+    ///
+    /// The directory is created when the first entry is written.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> ResponseCache {
+        ResponseCache {
+            dir: dir.into(),
+            negative_ttl: Duration::from_secs(DEFAULT_NEGATIVE_TTL_SECS),
+            max_size: DEFAULT_MAX_SIZE,
+            written: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Sets the time for which responses that were not found are cached (default: one day).
+    pub fn negative_ttl(mut self, ttl: Duration) -> ResponseCache {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    /// Sets the maximum total size of the cache in bytes (default: 1 GiB).
+    ///
+    /// The size is enforced by `prune`, which also runs whenever a sixteenth of the maximum size
+    /// has been written to the cache.
+    pub fn max_size(mut self, bytes: u64) -> ResponseCache {
+        self.max_size = bytes;
+        self
+    }
+
+    /// Returns the directory in which the entries of this cache are stored.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the file in which the response for the given URL is stored.
+    ///
+    /// Entries are spread over 256 subdirectories by the first byte of their hash, to keep the
+    /// directories reasonably small.
+    fn entry_path(&self, url: &str) -> PathBuf {
+        let hash = digest::digest(&SHA256, url.as_bytes());
+        let hex = hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        self.dir.join(&hex[..2]).join(&hex[2..])
+    }
+
+    /// Returns true if an entry that was last written at `modified` has expired.
+    fn expired(&self, modified: SystemTime) -> bool {
+        // entries with a modification time in the future are treated as fresh
+        modified.elapsed().map_or(false, |age| age > self.negative_ttl)
+    }
+
+    /// Looks up the response for the given URL.
+    ///
+    /// Returns `None` if there is no usable entry for the URL. Otherwise, returns the cached
+    /// response, which is `None` if the URL was not found and the content encoding and body of
+    /// the response otherwise. Entries that cannot be read are treated as missing.
+    ///
+    /// Reading a response that was found marks it as recently used, so that `prune` keeps it.
+    pub fn get(&self, url: &str) -> Option<Option<(String, Vec<u8>)>> {
+        let path = self.entry_path(url);
+        let mut file = File::open(&path).ok()?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).ok()?;
+
+        match contents.first() {
+            Some(&FOUND) => {
+                let newline = contents.iter().position(|&b| b == b'\n')?;
+                let encoding = String::from_utf8(contents[1..newline].to_vec()).ok()?;
+                let body = contents.split_off(newline + 1);
+                // the modification time is only used to find the least recently used entries
+                file.set_modified(SystemTime::now()).unwrap_or(());
+                Some(Some((encoding, body)))
+            }
+            Some(&NOT_FOUND) if contents.len() == 1 => {
+                let modified = file.metadata().and_then(|m| m.modified()).ok()?;
+                if self.expired(modified) {
+                    None
+                } else {
+                    Some(None)
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Stores the response for the given URL, which is `None` if the URL was not found and the
+    /// name of the content encoding and the body as it was received otherwise.
+    ///
+    /// The entry is written to a temporary file first and then moved into place, so that
+    /// concurrent readers never see a partially written entry.
+    pub fn put(&self, url: &str, response: Option<(&str, &[u8])>) -> io::Result<()> {
+        let path = self.entry_path(url);
+        let dir = path.parent().expect("cache entries are always in a subdirectory");
+        fs::create_dir_all(dir)?;
+
+        let tmp_path = dir.join(format!(".tmp-{}", process::id()));
+        let size = {
+            let mut file = File::create(&tmp_path)?;
+            match response {
+                Some((encoding, body)) => {
+                    file.write_all(&[FOUND])?;
+                    file.write_all(encoding.as_bytes())?;
+                    file.write_all(b"\n")?;
+                    file.write_all(body)?;
+                    (encoding.len() + body.len() + 2) as u64
+                }
+                None => {
+                    file.write_all(&[NOT_FOUND])?;
+                    1
+                }
+            }
+        };
+        fs::rename(&tmp_path, &path)?;
+
+        let written = self.written.fetch_add(size, Ordering::Relaxed) + size;
+        if written > self.max_size / PRUNE_FRACTION {
+            self.prune()?;
+        }
+        Ok(())
+    }
+
+    /// Removes expired entries for responses that were not found, and then removes the least
+    /// recently used entries until the total size of the cache is at most the maximum size.
+    ///
+    /// Returns the number of bytes that were freed.
+    pub fn prune(&self) -> io::Result<u64> {
+        self.written.store(0, Ordering::Relaxed);
+
+        let mut entries = Vec::new();
+        let shards = match fs::read_dir(&self.dir) {
+            Ok(shards) => shards,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        for shard in shards {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(shard.path())? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_file() {
+                    // read the first byte to find the entries for responses that were not found
+                    let mut kind = [0];
+                    let negative = File::open(entry.path()).and_then(|mut f| f.read_exact(&mut kind)).is_ok()
+                        && kind[0] == NOT_FOUND;
+                    entries.push((entry.path(), metadata.len(), metadata.modified()?, negative));
+                }
+            }
+        }
+
+        let mut freed = 0;
+        let mut size: u64 = entries.iter().map(|&(_, len, _, _)| len).sum();
+        entries.sort_by_key(|&(_, _, modified, _)| modified);
+        for (path, len, modified, negative) in entries {
+            if size > self.max_size || (negative && self.expired(modified)) {
+                fs::remove_file(&path)?;
+                size -= len;
+                freed += len;
+            }
+        }
+
+        Ok(freed)
+    }
+}
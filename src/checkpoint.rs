@@ -0,0 +1,290 @@
+//! Checkpoints for resuming an interrupted indexing run.
+//!
+//! Fetching the file listings for all of nixpkgs takes a long time. To avoid losing all progress
+//! when `nix-index` is interrupted, the state of the run is saved to a checkpoint directory
+//! periodically. A checkpoint directory contains:
+//!
+//! * `segment-N`: the file listings fetched so far, split into segments. Each segment is a complete
+//!   nix-index database, which is finished when the next checkpoint is saved.
+//! * `seen`: the hashes of all store paths that have been processed, one per line. A full run of
+//!   nixpkgs processes hundreds of thousands of store paths, so this file is only appended to:
+//!   each checkpoint adds the hashes of the paths processed since the previous one.
+//! * `state.json`: the state of the work queue at the time of the last checkpoint, as a JSON object
+//!   with the keys `seen` (the number of hashes in `seen` that belong to this state), `queue` (the
+//!   store paths that still need processing) and `segments` (the number of segments that belong to
+//!   this state). Segments and hashes written after the last checkpoint are discarded when resuming,
+//!   since the store paths they belong to are still part of the queue.
+//!
+//! The queue is rewritten for every checkpoint. It is largest at the beginning of a run, when the
+//! references of most store paths are not known yet, and shrinks from then on.
+//!
+//! When the run completes, the segments are combined into the final database and the checkpoint
+//! directory is removed. Since the segments are recompressed then, they are written with a fast
+//! compression level, so that saving checkpoints does not slow down the run.
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use serde_json;
+
+use database;
+use package::StorePath;
+use files::FileTree;
+
+error_chain! {
+    errors {
+        ReadState(path: PathBuf) {
+            description("checkpoint read error")
+            display("reading the checkpoint '{}' failed", path.to_string_lossy())
+        }
+        WriteState(path: PathBuf) {
+            description("checkpoint write error")
+            display("writing the checkpoint '{}' failed", path.to_string_lossy())
+        }
+        ReadSegment(path: PathBuf) {
+            description("checkpoint segment read error")
+            display("reading the checkpoint segment '{}' failed", path.to_string_lossy())
+        }
+        WriteSegment(path: PathBuf) {
+            description("checkpoint segment write error")
+            display("writing the checkpoint segment '{}' failed", path.to_string_lossy())
+        }
+    }
+
+    foreign_links {
+        Io(io::Error);
+    }
+}
+
+/// The zstd compression level for the segments.
+///
+/// Segments are only temporary, so writing them quickly matters more than their size.
+const SEGMENT_LEVEL: i32 = 1;
+
+/// The state of the work queue at the time of a checkpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+    /// The hashes of all store paths that have been processed.
+    pub seen: Vec<String>,
+    /// The store paths that still need processing.
+    pub queue: Vec<StorePath>,
+    /// The number of segments that contain the file listings fetched before this checkpoint.
+    pub segments: usize,
+}
+
+/// The contents of `state.json`, which refers to the hashes in the `seen` file by their number.
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    seen: usize,
+    queue: Vec<StorePath>,
+    segments: usize,
+}
+
+/// Writes checkpoints for an indexing run to a directory.
+pub struct Checkpointer {
+    /// The directory where the checkpoint is stored.
+    dir: PathBuf,
+    /// The number of segments that have been finished.
+    segments: usize,
+    /// The writer for the current segment, which is only created once there is a package to add.
+    writer: Option<database::Writer>,
+    /// The hashes that have already been written to the `seen` file.
+    saved: HashSet<String>,
+}
+
+impl Checkpointer {
+    /// Starts a new checkpoint in the given directory, removing any previous checkpoint there.
+    pub fn create<P: Into<PathBuf>>(dir: P) -> Result<Checkpointer> {
+        let dir = dir.into();
+        match fs::remove_dir_all(&dir) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            r => r?,
+        }
+        fs::create_dir_all(&dir)?;
+        Ok(Checkpointer {
+            dir: dir,
+            segments: 0,
+            writer: None,
+            saved: HashSet::new(),
+        })
+    }
+
+    /// Loads the checkpoint from the given directory, to continue an interrupted run.
+    ///
+    /// Returns `None` if there is no checkpoint in the directory. Segments and hashes that were
+    /// written after the last saved state are removed.
+    pub fn resume<P: Into<PathBuf>>(dir: P) -> Result<Option<(Checkpointer, State)>> {
+        let dir = dir.into();
+        let state_path = dir.join("state.json");
+        let file = match File::open(&state_path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).chain_err(|| ErrorKind::ReadState(state_path)),
+        };
+        let state: SavedState = serde_json::from_reader(BufReader::new(file))
+            .chain_err(|| ErrorKind::ReadState(state_path.clone()))?;
+
+        let seen_path = dir.join("seen");
+        let read_seen = || -> Result<Vec<String>> {
+            let mut seen = Vec::with_capacity(state.seen);
+            let mut len = 0;
+            let mut lines = BufReader::new(File::open(&seen_path)?).lines();
+            while seen.len() < state.seen {
+                let hash = lines.next().unwrap_or_else(|| {
+                    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "not enough hashes"))
+                })?;
+                len += hash.len() as u64 + 1;
+                seen.push(hash);
+            }
+            OpenOptions::new().write(true).open(&seen_path)?.set_len(len)?;
+            Ok(seen)
+        };
+        let seen = read_seen().chain_err(|| ErrorKind::ReadState(seen_path.clone()))?;
+
+        let checkpointer = Checkpointer {
+            dir: dir,
+            segments: state.segments,
+            writer: None,
+            saved: seen.iter().cloned().collect(),
+        };
+
+        let mut n = state.segments;
+        while checkpointer.segment_path(n).exists() {
+            fs::remove_file(checkpointer.segment_path(n))?;
+            n += 1;
+        }
+
+        let state = State {
+            seen: seen,
+            queue: state.queue,
+            segments: state.segments,
+        };
+        Ok(Some((checkpointer, state)))
+    }
+
+    /// Returns the path of the segment with the given number.
+    fn segment_path(&self, n: usize) -> PathBuf {
+        self.dir.join(format!("segment-{}", n))
+    }
+
+    /// Returns the directory where the checkpoint is stored.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Adds the file listing for a store path to the current segment.
+    pub fn add(&mut self, path: StorePath, files: FileTree) -> Result<()> {
+        let segment = self.segment_path(self.segments);
+        if self.writer.is_none() {
+            let writer = database::Writer::create(&segment, SEGMENT_LEVEL)
+                .chain_err(|| ErrorKind::WriteSegment(segment.clone()))?;
+            self.writer = Some(writer);
+        }
+
+        let writer = self.writer.as_mut().expect("writer was just created");
+        writer.add(path, files).chain_err(|| ErrorKind::WriteSegment(segment))
+    }
+
+    /// Finishes the current segment, if there is one.
+    fn finish_segment(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.take() {
+            let segment = self.segment_path(self.segments);
+            writer.finish().chain_err(|| ErrorKind::WriteSegment(segment))?;
+            self.segments += 1;
+        }
+        Ok(())
+    }
+
+    /// Saves a checkpoint with the given queue state.
+    ///
+    /// All file listings added so far must belong to store paths in `seen`, which must include the
+    /// `seen` hashes of all previous checkpoints. Only the new hashes are written. The state is replaced
+    /// atomically, so an interruption while saving leaves the previous checkpoint intact.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::env;
+    /// use nix_index::checkpoint::Checkpointer;
+    /// use nix_index::package::{PathOrigin, StorePath};
+    ///
+    /// let dir = env::temp_dir().join(format!("nix-index-checkpoint-doctest-{}", std::process::id()));
+    /// let origin = PathOrigin { attr: "hello".to_string(), output: "out".to_string(), toplevel: true };
+    /// let hello = StorePath::parse(origin, "/nix/store/cccccccccccccccccccccccccccccccc-hello-2.10").unwrap();
+    ///
+    /// let mut checkpointer = Checkpointer::create(&dir).unwrap();
+    /// checkpointer.save(vec!["a".repeat(32)], vec![hello.clone()]).unwrap();
+    /// checkpointer.save(vec!["a".repeat(32), "b".repeat(32)], vec![hello.clone()]).unwrap();
+    ///
+    /// let (mut checkpointer, state) = Checkpointer::resume(&dir).unwrap().unwrap();
+    /// assert_eq!(state.seen, vec!["a".repeat(32), "b".repeat(32)]);
+    /// assert_eq!(state.queue, vec![hello]);
+    ///
+    /// // the hashes are only written once, even if they are part of `seen` again
+    /// checkpointer.save(vec!["b".repeat(32), "c".repeat(32), "a".repeat(32)], vec![]).unwrap();
+    /// let (checkpointer, state) = Checkpointer::resume(&dir).unwrap().unwrap();
+    /// assert_eq!(state.seen, vec!["a".repeat(32), "b".repeat(32), "c".repeat(32)]);
+    /// assert!(state.queue.is_empty());
+    ///
+    /// let mut db = nix_index::database::Writer::create(dir.with_extension("db"), 1).unwrap();
+    /// assert_eq!(checkpointer.finish(&mut db).unwrap(), 0);
+    /// assert!(!dir.exists());
+    /// # std::fs::remove_file(dir.with_extension("db")).unwrap();
+    /// ```
+    pub fn save(&mut self, seen: Vec<String>, queue: Vec<StorePath>) -> Result<()> {
+        self.finish_segment()?;
+
+        let seen_path = self.dir.join("seen");
+        let append = || -> io::Result<()> {
+            let file = OpenOptions::new().create(true).append(true).open(&seen_path)?;
+            let mut output = BufWriter::new(file);
+            for hash in seen {
+                if !self.saved.contains(&hash) {
+                    writeln!(output, "{}", hash)?;
+                    self.saved.insert(hash);
+                }
+            }
+            output.flush()
+        };
+        append().chain_err(|| ErrorKind::WriteState(seen_path.clone()))?;
+
+        let state = SavedState {
+            seen: self.saved.len(),
+            queue: queue,
+            segments: self.segments,
+        };
+        let state_path = self.dir.join("state.json");
+        let tmp_path = self.dir.join("state.json.tmp");
+        let write = || -> Result<()> {
+            let mut output = BufWriter::new(File::create(&tmp_path)?);
+            serde_json::to_writer(&mut output, &state).map_err(io::Error::from)?;
+            output.flush()?;
+            fs::rename(&tmp_path, &state_path)?;
+            Ok(())
+        };
+        write().chain_err(|| ErrorKind::WriteState(state_path.clone()))
+    }
+
+    /// Copies the file listings of all segments into the given database and removes the checkpoint.
+    ///
+    /// Returns the number of packages that were copied.
+    pub fn finish(mut self, db: &mut database::Writer) -> Result<usize> {
+        self.finish_segment()?;
+
+        let mut count = 0;
+        for n in 0..self.segments {
+            let segment = self.segment_path(n);
+            let packages = database::Reader::open(&segment)
+                .chain_err(|| ErrorKind::ReadSegment(segment.clone()))?
+                .packages();
+            for package in packages {
+                let (path, entries) = package.chain_err(|| ErrorKind::ReadSegment(segment.clone()))?;
+                db.add_entries(path, entries)?;
+                count += 1;
+            }
+        }
+
+        fs::remove_dir_all(&self.dir)?;
+        Ok(count)
+    }
+}
@@ -11,6 +11,7 @@ use regex_syntax::Expr;
 use regex::bytes::Regex;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use serde_json;
+use memchr::memchr;
 
 use package::StorePath;
 use files::{FileTree, FileTreeEntry};
@@ -19,7 +20,7 @@ use frcode;
 /// The version of the database format supported by this nix-index version.
 ///
 /// This should be updated whenever you make an incompatible change to the database format.
-const FORMAT_VERSION: u64 = 1;
+pub const FORMAT_VERSION: u64 = 1;
 
 /// The magic for nix-index database files, used to ensure that the file we're passed is
 /// actually a file generated by nix-index.
@@ -58,10 +59,18 @@ impl Writer {
     /// Add a new package to the database for the given store path with its corresponding
     /// file tree.
     pub fn add(&mut self, path: StorePath, files: FileTree) -> io::Result<()> {
+        self.add_entries(path, files.to_list())
+    }
+
+    /// Add a new package to the database for the given store path with the given file entries.
+    ///
+    /// This is useful for copying packages from another database, where the entries are available
+    /// directly (see `Reader::packages`).
+    pub fn add_entries(&mut self, path: StorePath, entries: Vec<FileTreeEntry>) -> io::Result<()> {
         let writer = self.writer.as_mut().expect("not dropped yet");
         let mut encoder =
             frcode::Encoder::new(writer, b"p".to_vec(), serde_json::to_vec(&path).unwrap());
-        for entry in files.to_list() {
+        for entry in entries {
             entry.encode(&mut encoder)?;
         }
         Ok(())
@@ -165,6 +174,25 @@ impl Reader {
         }
     }
 
+    /// Builds an iterator over all packages in the database.
+    ///
+    /// Each item is a package together with all the entries of its file tree. In contrast to `query`,
+    /// this does not search for a pattern but yields the complete contents of the database.
+    ///
+    /// Use `PackageIter::hash`, `PackageIter::attr` and `PackageIter::toplevel` to only list specific packages.
+    pub fn packages(self) -> PackageIter {
+        PackageIter {
+            reader: self,
+            block: Vec::new(),
+            pos: 0,
+            entries: Vec::new(),
+            hash: None,
+            attr: None,
+            toplevel: false,
+            done: false,
+        }
+    }
+
     /// Dumps the contents of the database to stdout, for debugging.
     #[allow(clippy::print_stdout)]
     pub fn dump(&mut self) -> Result<()> {
@@ -409,3 +437,192 @@ impl<'a, 'b> Iterator for ReaderIter<'a, 'b> {
         }
     }
 }
+
+/// An iterator over all packages in a database, together with their file entries.
+///
+/// Use `Reader::packages` to construct a value of this type.
+pub struct PackageIter {
+    /// The underlying reader from which we read input.
+    reader: Reader,
+    /// The block of entries that is currently being processed.
+    ///
+    /// We need our own copy of the block since the decoder reuses its buffer for the next block,
+    /// but the entries of a single package may span multiple blocks.
+    block: Vec<u8>,
+    /// The position in `block` where the next entry starts.
+    pos: usize,
+    /// The raw, newline-terminated file entries that were read since the last package entry.
+    ///
+    /// Since the package entry is stored after all file entries of a package, we have to
+    /// collect the file entries until we reach the package they belong to.
+    entries: Vec<u8>,
+    /// Only include the package with the given hash.
+    hash: Option<String>,
+    /// Only include packages for the given attribute.
+    attr: Option<String>,
+    /// Only include top-level packages.
+    toplevel: bool,
+    /// Set when we know that no more packages can match, so we can stop reading.
+    done: bool,
+}
+
+impl PackageIter {
+    /// Limit results to the package with the specified hash if `Some`.
+    ///
+    /// Since store path hashes are unique, the iterator stops as soon as this package is found.
+    pub fn hash(self, hash: Option<String>) -> PackageIter {
+        PackageIter { hash: hash, ..self }
+    }
+
+    /// Limit results to packages for the specified attribute if `Some`.
+    ///
+    /// The attribute can either be given with an output, like `coreutils.out`, or without,
+    /// in which case all outputs of the attribute match. Packages that are not top-level match
+    /// as well, such as the dependencies of a top-level package (which have the same attribute) and
+    /// store paths that were indexed without evaluating nixpkgs (which are named after the store
+    /// path, see `PathOrigin::from_name`). Use `toplevel` to exclude them.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::env;
+    /// use std::fs;
+    /// use nix_index::database::{Reader, Writer};
+    /// use nix_index::files::FileTree;
+    /// use nix_index::nixpkgs;
+    /// use nix_index::package::{PathOrigin, StorePath};
+    ///
+    /// let dir = env::temp_dir().join(format!("nix-index-packages-doctest-{}", std::process::id()));
+    /// fs::create_dir_all(&dir).unwrap();
+    /// let file = dir.join("files");
+    ///
+    /// let hello = PathOrigin { attr: "hello".to_string(), output: "out".to_string(), toplevel: true };
+    /// let dependency = PathOrigin { toplevel: false, ..hello.clone() };
+    /// // a store path given with --local, which is named after itself
+    /// let local = PathOrigin::from_name("hello-2.10");
+    ///
+    /// let mut db = Writer::create(&file, 1).unwrap();
+    /// for (origin, path) in vec![
+    ///     (hello, "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.10"),
+    ///     (dependency, "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-glibc-2.27"),
+    ///     (local, "/nix/store/cccccccccccccccccccccccccccccccc-hello-2.10"),
+    /// ] {
+    ///     db.add(StorePath::parse(origin, path).unwrap(), FileTree::regular(0, false)).unwrap();
+    /// }
+    /// // a store path from a list given with --paths-from
+    /// let list = "/nix/store/dddddddddddddddddddddddddddddddd-openssl-3.0.7-dev\n";
+    /// for path in nixpkgs::read_paths(list.as_bytes()).unwrap() {
+    ///     db.add(path, FileTree::regular(0, false)).unwrap();
+    /// }
+    /// db.finish().unwrap();
+    ///
+    /// let list = |attr: &str, toplevel: bool| -> Vec<String> {
+    ///     Reader::open(&file).unwrap()
+    ///         .packages()
+    ///         .attr(Some(attr.to_string()))
+    ///         .toplevel(toplevel)
+    ///         .map(|package| package.unwrap().0.hash().into_owned())
+    ///         .collect()
+    /// };
+    /// assert_eq!(list("hello", false), vec!["a".repeat(32), "b".repeat(32)]);
+    /// assert_eq!(list("hello", true), vec!["a".repeat(32)]);
+    /// assert_eq!(list("hello-2.10.out", false), vec!["c".repeat(32)]);
+    /// assert!(list("hello-2.10", true).is_empty());
+    /// assert_eq!(list("openssl-3.0.7", false), vec!["d".repeat(32)]);
+    /// assert_eq!(list("openssl-3.0.7.dev", false), vec!["d".repeat(32)]);
+    ///
+    /// fs::remove_dir_all(&dir).unwrap();
+    /// ```
+    pub fn attr(self, attr: Option<String>) -> PackageIter {
+        PackageIter { attr: attr, ..self }
+    }
+
+    /// Limit results to top-level packages (see `PathOrigin::toplevel`) if `true`.
+    pub fn toplevel(self, toplevel: bool) -> PackageIter {
+        PackageIter { toplevel: toplevel, ..self }
+    }
+
+    /// Tests if a store path matches the `hash`, `attr` and `toplevel` constraints.
+    fn should_include(&self, pkg: &StorePath) -> bool {
+        let origin = pkg.origin();
+        self.hash.as_ref().map_or(true, |h| h == &pkg.hash()) &&
+            (!self.toplevel || origin.toplevel) &&
+            self.attr.as_ref().map_or(true, |a| {
+                a == &origin.attr || *a == format!("{}.{}", origin.attr, origin.output)
+            })
+    }
+
+    /// Reads input until the next package entry and returns that package together with its file entries.
+    ///
+    /// Returns `None` if the end of the input has been reached.
+    fn next_package(&mut self) -> Result<Option<(StorePath, Vec<FileTreeEntry>)>> {
+        loop {
+            if self.done {
+                return Ok(None);
+            }
+
+            if self.pos >= self.block.len() {
+                let block = self.reader.decoder.decode()?;
+
+                // if the block is empty, the end of input has been reached
+                if block.is_empty() {
+                    if !self.entries.is_empty() {
+                        return Err(ErrorKind::MissingPackageEntry.into());
+                    }
+                    return Ok(None);
+                }
+
+                self.block.clear();
+                self.block.extend_from_slice(block);
+                self.pos = 0;
+            }
+
+            let start = self.pos;
+            let end = memchr(b'\n', &self.block[start..]).map_or(self.block.len(), |i| start + i);
+            self.pos = end + 1;
+
+            let line = &self.block[start..end];
+            if !line.starts_with(b"p\0") {
+                self.entries.extend_from_slice(line);
+                self.entries.push(b'\n');
+                continue;
+            }
+
+            let json = &line[2..];
+            let pkg: StorePath = serde_json::from_slice(json).chain_err(|| {
+                ErrorKind::StorePathParse(json.to_vec())
+            })?;
+
+            // skip decoding the entries of packages that we're not interested in
+            if !self.should_include(&pkg) {
+                self.entries.clear();
+                continue;
+            }
+            self.done = self.hash.is_some();
+
+            let entries = self.entries
+                .split(|c| *c == b'\n')
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| {
+                    FileTreeEntry::decode(entry).ok_or_else(|| {
+                        Error::from(ErrorKind::EntryParse(entry.to_vec()))
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            self.entries.clear();
+
+            return Ok(Some((pkg, entries)));
+        }
+    }
+}
+
+impl Iterator for PackageIter {
+    type Item = Result<(StorePath, Vec<FileTreeEntry>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_package() {
+            Err(e) => Some(Err(e)),
+            Ok(v) => v.map(Ok),
+        }
+    }
+}
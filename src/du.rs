@@ -0,0 +1,104 @@
+//! Disk usage of packages, computed from the file listings in the index.
+//!
+//! Since the index records the size of every regular file, we can tell how much space a package
+//! needs (and which directories and files are responsible for it) without downloading it.
+//! Sizes are apparent sizes: symlinks and directories themselves are counted as zero bytes,
+//! and the targets of symlinks are not followed.
+use std::cmp::Reverse;
+
+use files::{FileNode, FileTree};
+
+/// The aggregated disk usage of a node in a file tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskUsage {
+    /// The file name of the node. This is empty for the root of a tree.
+    pub name: Vec<u8>,
+    /// The total size of all regular files below this node (or of the node itself, if it is a file).
+    pub size: u64,
+    /// The number of regular files below this node.
+    pub files: u64,
+    /// The disk usage of the children of this node, largest first. Empty unless the node is a directory.
+    pub children: Vec<DiskUsage>,
+}
+
+impl DiskUsage {
+    /// Computes the disk usage of all nodes in the given tree.
+    pub fn of(tree: &FileTree) -> DiskUsage {
+        DiskUsage::node(Vec::new(), tree)
+    }
+
+    fn node(name: Vec<u8>, tree: &FileTree) -> DiskUsage {
+        match *tree.node() {
+            FileNode::Regular { size, .. } => DiskUsage {
+                name: name,
+                size: size,
+                files: 1,
+                children: Vec::new(),
+            },
+            FileNode::Symlink { .. } => DiskUsage {
+                name: name,
+                size: 0,
+                files: 0,
+                children: Vec::new(),
+            },
+            FileNode::Directory { ref contents, .. } => {
+                let mut children = contents
+                    .iter()
+                    .map(|(child, subtree)| DiskUsage::node(child.to_vec(), subtree))
+                    .collect::<Vec<_>>();
+                children.sort_by(|a, b| Ord::cmp(&(Reverse(a.size), &a.name), &(Reverse(b.size), &b.name)));
+
+                DiskUsage {
+                    name: name,
+                    size: children.iter().map(|c| c.size).sum(),
+                    files: children.iter().map(|c| c.files).sum(),
+                    children: children,
+                }
+            }
+        }
+    }
+}
+
+/// Returns the `n` largest regular files of a tree as pairs of path and size, largest first.
+///
+/// Paths start with a `/` and are relative to the root of the tree.
+pub fn largest_files(tree: &FileTree, n: usize) -> Vec<(Vec<u8>, u64)> {
+    let mut files = tree
+        .to_list()
+        .into_iter()
+        .filter_map(|entry| match entry.node {
+            FileNode::Regular { size, .. } => Some((entry.path, size)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    files.sort_by(|a, b| Ord::cmp(&(Reverse(a.1), &a.0), &(Reverse(b.1), &b.0)));
+    files.truncate(n);
+    files
+}
+
+/// Formats a size in bytes with binary units, like `du -h` does.
+///
+/// # Example
+///
+/// ```
+/// use nix_index::du::human_size;
+///
+/// assert_eq!(human_size(512), "512 B");
+/// assert_eq!(human_size(1536), "1.5 KiB");
+/// assert_eq!(human_size(3 * 1024 * 1024), "3.0 MiB");
+/// ```
+pub fn human_size(size: u64) -> String {
+    const UNITS: [&'static str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
@@ -216,6 +216,68 @@ impl FileTree {
         })
     }
 
+    /// Builds a file tree from a list of entries, such as the list produced by `to_list`.
+    ///
+    /// The entries may be given in any order. Returns `None` if there is no entry for the
+    /// root of the tree or if the parent of some entry is not a directory.
+    pub fn from_list(mut entries: Vec<FileTreeEntry>) -> Option<Self> {
+        // sorting by path guarantees that directories come before their contents
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut entries = entries.into_iter();
+        let mut tree = match entries.next() {
+            Some(FileTreeEntry { ref path, ref node }) if path.is_empty() => FileTree::from_node(node),
+            _ => return None,
+        };
+
+        for FileTreeEntry { path, node } in entries {
+            tree.insert(&path, &node)?;
+        }
+        Some(tree)
+    }
+
+    /// Creates a tree consisting of just the given node. If the node is a directory,
+    /// the directory will be empty.
+    fn from_node(node: &FileNode<()>) -> Self {
+        match *node {
+            FileNode::Regular { size, executable } => FileTree::regular(size, executable),
+            FileNode::Symlink { ref target } => FileTree::symlink(target.clone()),
+            FileNode::Directory { .. } => FileTree::directory(HashMap::new()),
+        }
+    }
+
+    /// Inserts a node at the given path, which must start with a `/`.
+    ///
+    /// All parent directories of the path need to exist already, otherwise `None` is returned.
+    fn insert(&mut self, path: &[u8], node: &FileNode<()>) -> Option<()> {
+        let mut components = path.split(|c| *c == b'/').skip(1).collect::<Vec<_>>();
+        let name = components.pop()?;
+
+        let mut current = self;
+        for component in components {
+            current = match current.0 {
+                FileNode::Directory { ref mut contents, .. } => {
+                    contents.get_mut(&ByteBuf::from(component.to_vec()))?
+                }
+                _ => return None,
+            };
+        }
+
+        match current.0 {
+            FileNode::Directory { ref mut size, ref mut contents } => {
+                contents.insert(ByteBuf::from(name.to_vec()), FileTree::from_node(node));
+                *size = contents.len() as u64;
+                Some(())
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the node at the root of this tree.
+    pub fn node(&self) -> &FileNode<HashMap<ByteBuf, FileTree>> {
+        &self.0
+    }
+
     pub fn to_list(&self) -> Vec<FileTreeEntry> {
         let mut result = Vec::new();
 
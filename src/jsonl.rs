@@ -0,0 +1,133 @@
+//! A JSON Lines interchange format for nix-index databases.
+//!
+//! This format is meant for exchanging file listings with other tools. It can be produced
+//! with `nix-index export --jsonl` and read back with `nix-index import --jsonl`, which makes it
+//! possible to add file listings for packages that are not available from any binary cache.
+//!
+//! Each line of the input is a JSON object describing a single package, with two keys:
+//!
+//! * `store_path`: the store path of the package, as an object with the keys `store_dir`, `hash`, `name` and
+//!   `origin`. The origin is an object with the keys `attr`, `output` and `toplevel` (see `PathOrigin`).
+//!   The optional key `source` holds the URL of the binary cache that the package was fetched from and
+//!   the optional key `verified` whether its signature was valid (see `StorePath::verified`).
+//! * `root`: the file listing of the package, in the same format as the `root` key of the `.ls` files
+//!   in the binary cache.
+//!
+//! Example (formatted over multiple lines for readability, each package must be on a single line):
+//!
+//! ```text
+//! {"store_path": {"store_dir": "/nix/store", "hash": "bdjyhh70npndlq3rzmggh4f2dzdsj4xy", "name": "hello-2.10",
+//!                 "origin": {"attr": "hello", "output": "out", "toplevel": true}},
+//!  "root": {"type": "directory", "entries": {
+//!    "bin": {"type": "directory", "entries": {"hello": {"type": "regular", "size": 29488, "executable": true}}},
+//!    "share": {"type": "symlink", "target": "/nix/store/...-hello-share"}}}}
+//! ```
+//!
+//! Empty lines are ignored. Since file names are JSON strings, file names and symlink targets that are not
+//! valid UTF-8 cannot be represented exactly. On export, invalid bytes are replaced with the unicode replacement
+//! character, and the original bytes are added to the entry as base64 in the `name_base64` or `target_base64`
+//! key, which replace the name or target on import (see `HydraFileListing`). So an export followed by an import
+//! reproduces the database exactly:
+//!
+//! ```text
+//! "caf\uFFFD": {"type": "regular", "size": 3, "executable": false, "name_base64": "Y2Fm6Q=="}
+//! ```
+use std::io::{self, BufRead, Write};
+use serde_json;
+
+use database;
+use files::FileTree;
+use hydra::HydraFileListing;
+use package::StorePath;
+
+error_chain! {
+    errors {
+        ParseLine(line: usize) {
+            description("line parse error")
+            display("line {} is not a valid package entry", line)
+        }
+        MissingRoot(path: String) {
+            description("missing root entry")
+            display("database corrupt, the file listing of '{}' does not have a root entry", path)
+        }
+    }
+
+    links {
+        Database(database::Error, database::ErrorKind);
+    }
+
+    foreign_links {
+        Io(io::Error);
+        Json(serde_json::Error);
+    }
+}
+
+/// The structure of a single line of the format.
+#[derive(Serialize, Deserialize)]
+struct Line {
+    store_path: StorePath,
+    root: HydraFileListing,
+}
+
+/// Writes all packages produced by `packages` to the given writer, one package per line.
+///
+/// Returns the number of packages that were written.
+pub fn export<W: Write>(packages: database::PackageIter, mut writer: W) -> Result<u64> {
+    let mut count = 0;
+    for package in packages {
+        let (store_path, entries) = package?;
+        let root = FileTree::from_list(entries)
+            .ok_or_else(|| ErrorKind::MissingRoot(store_path.as_str().into_owned()))?;
+        let line = Line {
+            store_path: store_path,
+            root: HydraFileListing(root),
+        };
+        serde_json::to_writer(&mut writer, &line)?;
+        writer.write_all(b"\n")?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// An iterator over the packages of an input in the JSON Lines format.
+///
+/// Use `import` to construct a value of this type.
+pub struct Import<R> {
+    lines: io::Lines<R>,
+    /// The number of the line that was read last, for error messages.
+    line: usize,
+}
+
+/// Reads packages in the JSON Lines format from the given reader.
+pub fn import<R: BufRead>(reader: R) -> Import<R> {
+    Import {
+        lines: reader.lines(),
+        line: 0,
+    }
+}
+
+impl<R: BufRead> Iterator for Import<R> {
+    type Item = Result<(StorePath, FileTree)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            self.line += 1;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let number = self.line;
+            return Some(
+                serde_json::from_str(&line)
+                    .chain_err(|| ErrorKind::ParseLine(number))
+                    .map(|Line { store_path, root }| (store_path, root.0)),
+            );
+        }
+    }
+}
@@ -14,8 +14,10 @@
 #[macro_use]
 extern crate serde_derive;
 extern crate ansi_term;
+extern crate base64;
 extern crate bincode;
 extern crate byteorder;
+extern crate bzip2;
 extern crate futures;
 extern crate grep;
 extern crate hyper;
@@ -24,6 +26,10 @@ extern crate memchr;
 extern crate ordermap;
 extern crate regex;
 extern crate regex_syntax;
+extern crate ring;
+#[macro_use]
+extern crate rusqlite;
+extern crate separator;
 extern crate serde;
 extern crate serde_bytes;
 extern crate serde_json;
@@ -39,11 +45,27 @@ extern crate error_chain;
 extern crate brotli2;
 extern crate url;
 
+pub mod auth;
+pub mod cache;
+pub mod checkpoint;
 pub mod database;
+pub mod du;
 pub mod files;
 pub mod frcode;
 pub mod hydra;
+pub mod jsonl;
+pub mod local;
+pub mod nar;
 pub mod nixpkgs;
 pub mod package;
+pub mod progress;
+pub mod proxy;
+pub mod report;
+pub mod s3;
+pub mod signing;
+pub mod sqlite;
+pub mod stats;
+pub mod throttle;
+pub mod transport;
 pub mod util;
 pub mod workset;
@@ -0,0 +1,159 @@
+//! Reading file listings directly from a local Nix store.
+//!
+//! This makes it possible to index paths that are not available from any binary cache,
+//! such as locally built packages, and to build an index on machines without network access.
+//!
+//! Since we do not evaluate nixpkgs for local paths, we do not know their attribute paths.
+//! Instead, each path given by the user is named after the store path (for example `hello-2.10`
+//! with output `out`, see `PathOrigin::from_name`). Paths in the closure of such a path get the
+//! same origin. None of these paths are marked as top-level, since their names are not attributes
+//! that could be installed.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str;
+use serde_bytes::ByteBuf;
+
+use files::FileTree;
+use package::{PathOrigin, StorePath};
+
+error_chain! {
+    errors {
+        NotAStorePath(path: String) {
+            description("not a store path")
+            display("'{}' is not a store path", path)
+        }
+        QueryRequisites(path: String) {
+            description("nix-store query error")
+            display("querying the closure of '{}' with nix-store failed", path)
+        }
+        NixStore(message: String) {
+            description("nix-store error")
+            display("nix-store failed with error: {}", message)
+        }
+    }
+
+    foreign_links {
+        Io(io::Error);
+    }
+}
+
+/// Returns the origin that we assign to a path given by the user, which is named after the store path.
+fn root_origin(path: &StorePath) -> PathOrigin {
+    PathOrigin::from_name(&path.name())
+}
+
+/// Parses a store path with the given origin, failing with a `NotAStorePath` error if it cannot be parsed.
+fn parse_store_path(origin: PathOrigin, path: &str) -> Result<StorePath> {
+    StorePath::parse(origin, path).ok_or_else(|| ErrorKind::NotAStorePath(path.to_string()).into())
+}
+
+/// Resolves a path that refers to a store path, such as `/run/current-system` or `~/.nix-profile`,
+/// by following all symlinks. The resolved path must be a store path itself, not a file inside a store path.
+///
+/// The returned store path is named after the store path itself (see `PathOrigin::from_name`).
+pub fn resolve_root<P: AsRef<Path>>(path: P) -> Result<StorePath> {
+    let path = path.as_ref();
+    let display = path.to_string_lossy().into_owned();
+    let resolved = fs::canonicalize(path).chain_err(|| ErrorKind::NotAStorePath(display.clone()))?;
+    let resolved = resolved.to_str().ok_or_else(|| ErrorKind::NotAStorePath(display.clone()))?;
+
+    let dummy = PathOrigin {
+        attr: String::new(),
+        output: String::new(),
+        toplevel: true,
+    };
+    let store_path = parse_store_path(dummy, resolved)?;
+    if store_path.store_dir().is_empty() || store_path.name().contains('/') {
+        bail!(ErrorKind::NotAStorePath(display));
+    }
+
+    let origin = root_origin(&store_path);
+    parse_store_path(origin, resolved)
+}
+
+/// Calls `nix-store --query --requisites` to get the closure of the given store path.
+fn query_requisites(root: &StorePath) -> Result<Vec<String>> {
+    let output = Command::new("nix-store")
+        .arg("--query")
+        .arg("--requisites")
+        .arg(root.as_str().as_ref())
+        .stdin(Stdio::null())
+        .output()?;
+
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        bail!(ErrorKind::NixStore(message));
+    }
+
+    let stdout = str::from_utf8(&output.stdout).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(stdout.lines().filter(|line| !line.is_empty()).map(|line| line.to_string()).collect())
+}
+
+/// Computes the union of the closures of the given roots, using `nix-store`.
+///
+/// Roots may be anything accepted by `resolve_root`. Paths that are part of the closure of multiple
+/// roots get the origin of the first such root.
+pub fn query_closure<P: AsRef<Path>>(roots: &[P]) -> Result<Vec<StorePath>> {
+    let mut result: Vec<StorePath> = Vec::new();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    for root in roots {
+        let root = resolve_root(root)?;
+        let origin = root.origin().into_owned();
+
+        let requisites = query_requisites(&root)
+            .chain_err(|| ErrorKind::QueryRequisites(root.as_str().into_owned()))?;
+        for path in requisites {
+            let path = parse_store_path(origin.clone(), &path)?;
+            let index = result.len();
+            if *seen.entry(path.hash().into_owned()).or_insert(index) == index {
+                result.push(path);
+            }
+        }
+
+        // the root is named after itself, even if it was already seen as a dependency of another root
+        let hash = root.hash().into_owned();
+        match seen.get(&hash) {
+            Some(&index) => result[index] = root,
+            None => {
+                seen.insert(hash, result.len());
+                result.push(root);
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Reads the file tree rooted at the given path from the file system.
+///
+/// Symlinks are not followed. For regular files, the size and executable bit are taken from the
+/// file metadata. If any entry cannot be read, an error is returned.
+pub fn read_tree<P: AsRef<Path>>(path: P) -> io::Result<FileTree> {
+    let path = path.as_ref();
+    let metadata = fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+
+    if file_type.is_symlink() {
+        let target = fs::read_link(path)?;
+        return Ok(FileTree::symlink(ByteBuf::from(target.as_os_str().as_bytes().to_vec())));
+    }
+
+    if file_type.is_dir() {
+        let mut entries = HashMap::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name().as_bytes().to_vec();
+            entries.insert(ByteBuf::from(name), read_tree(entry.path())?);
+        }
+        return Ok(FileTree::directory(entries));
+    }
+
+    let executable = metadata.permissions().mode() & 0o111 != 0;
+    Ok(FileTree::regular(metadata.len(), executable))
+}
@@ -0,0 +1,224 @@
+//! Reading file listings from NAR archives.
+//!
+//! Binary caches usually provide a `.ls` file with the file listing of each store path, but
+//! older caches and many self-hosted ones do not. For those, the file listing can be generated
+//! from the NAR (Nix ARchive) of the store path instead. This module implements a streaming parser
+//! for the NAR format that only records the structure of the archive: file contents are skipped.
+//!
+//! A NAR is a sequence of strings, each encoded as a 64-bit little endian length followed by the
+//! bytes of the string, padded with zero bytes to a multiple of 8 bytes. The archive starts with the
+//! string `nix-archive-1`, followed by the root node:
+//!
+//! ```text
+//! node      = "(" "type" ( regular | symlink | directory ) ")"
+//! regular   = "regular" [ "executable" "" ] "contents" <contents>
+//! symlink   = "symlink" "target" <target>
+//! directory = "directory" { "entry" "(" "name" <name> "node" node ")" }
+//! ```
+use std::collections::HashMap;
+use std::io::{self, Read};
+use byteorder::{LittleEndian, ReadBytesExt};
+use bzip2::read::BzDecoder;
+use serde_bytes::ByteBuf;
+use xz2::read::XzDecoder;
+use zstd;
+
+use files::FileTree;
+
+error_chain! {
+    errors {
+        UnsupportedCompression(compression: String) {
+            description("unsupported nar compression")
+            display("the NAR compression '{}' is not supported", compression)
+        }
+        Format(message: String) {
+            description("nar format error")
+            display("invalid NAR: {}", message)
+        }
+    }
+
+    foreign_links {
+        Io(io::Error);
+    }
+}
+
+/// The magic string at the start of every NAR.
+const NAR_MAGIC: &'static [u8] = b"nix-archive-1";
+
+/// The maximum length of a string in the NAR, other than file contents.
+///
+/// Names and symlink targets are much shorter in practice. This limit protects against
+/// allocating huge buffers for corrupt archives.
+const MAX_STRING_LEN: u64 = 64 * 1024;
+
+/// Returns a reader that decompresses a NAR compressed with the given compression method.
+///
+/// The compression method is the `Compression` field of a narinfo. Supported are `xz`, `zstd`,
+/// `bzip2` and `none`.
+pub fn decompress<'a, R: Read + 'a>(compression: &str, reader: R) -> Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        "none" => Box::new(reader),
+        "xz" => Box::new(XzDecoder::new(reader)),
+        "bzip2" => Box::new(BzDecoder::new(reader)),
+        "zstd" => Box::new(zstd::Decoder::new(reader)?),
+        _ => bail!(ErrorKind::UnsupportedCompression(compression.to_string())),
+    })
+}
+
+/// Parses an uncompressed NAR from the given reader and returns the file tree it contains.
+///
+/// # Example
+///
+/// ```
+/// use nix_index::files::FileTree;
+/// use nix_index::nar;
+///
+/// // encodes a string as it is stored in a NAR
+/// fn string(s: &[u8]) -> Vec<u8> {
+///     let mut v = (s.len() as u64).to_le_bytes().to_vec();
+///     v.extend_from_slice(s);
+///     v.resize((v.len() + 7) / 8 * 8, 0);
+///     v
+/// }
+///
+/// let parts: &[&[u8]] = &[
+///     b"nix-archive-1", b"(", b"type", b"regular", b"executable", b"", b"contents", b"hello", b")",
+/// ];
+/// let archive = parts.iter().flat_map(|s| string(s)).collect::<Vec<u8>>();
+///
+/// assert_eq!(nar::read_tree(&archive[..]).unwrap(), FileTree::regular(5, true));
+/// assert!(nar::read_tree(&archive[..40]).is_err());
+/// ```
+pub fn read_tree<R: Read>(reader: R) -> Result<FileTree> {
+    let mut parser = Parser { reader: reader };
+    parser.expect(NAR_MAGIC)?;
+    parser.node()
+}
+
+/// The state of the NAR parser.
+struct Parser<R> {
+    reader: R,
+}
+
+impl<R: Read> Parser<R> {
+    /// Skips the zero bytes that pad a string of the given length to a multiple of 8 bytes.
+    fn skip_padding(&mut self, len: u64) -> Result<()> {
+        let padding = (8 - len % 8) % 8;
+        let mut buf = [0u8; 8];
+        self.reader.read_exact(&mut buf[..padding as usize])?;
+        if buf.iter().any(|b| *b != 0) {
+            bail!(ErrorKind::Format("non-zero padding".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Reads a string, which must not be longer than `MAX_STRING_LEN`.
+    fn string(&mut self) -> Result<Vec<u8>> {
+        let len = self.reader.read_u64::<LittleEndian>()?;
+        if len > MAX_STRING_LEN {
+            bail!(ErrorKind::Format(format!("string of length {} is too long", len)));
+        }
+        let mut buf = vec![0; len as usize];
+        self.reader.read_exact(&mut buf)?;
+        self.skip_padding(len)?;
+        Ok(buf)
+    }
+
+    /// Reads a string and fails if it is not equal to `expected`.
+    fn expect(&mut self, expected: &[u8]) -> Result<()> {
+        let found = self.string()?;
+        if found != expected {
+            bail!(ErrorKind::Format(format!(
+                "expected '{}', found '{}'",
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(&found)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Skips the contents of a regular file and returns their size.
+    fn skip_contents(&mut self) -> Result<u64> {
+        let len = self.reader.read_u64::<LittleEndian>()?;
+        let skipped = io::copy(&mut (&mut self.reader).take(len), &mut io::sink())?;
+        if skipped != len {
+            bail!(ErrorKind::Format("unexpected end of file contents".to_string()));
+        }
+        self.skip_padding(len)?;
+        Ok(len)
+    }
+
+    /// Parses a node (the part enclosed in parentheses), including its children.
+    fn node(&mut self) -> Result<FileTree> {
+        self.expect(b"(")?;
+        self.expect(b"type")?;
+
+        let tree = match &self.string()?[..] {
+            b"regular" => {
+                let mut executable = false;
+                let mut tag = self.string()?;
+                if tag == b"executable" {
+                    executable = true;
+                    self.expect(b"")?;
+                    tag = self.string()?;
+                }
+                if tag != b"contents" {
+                    bail!(ErrorKind::Format(format!(
+                        "expected 'contents', found '{}'",
+                        String::from_utf8_lossy(&tag)
+                    )));
+                }
+                let size = self.skip_contents()?;
+                self.expect(b")")?;
+                FileTree::regular(size, executable)
+            }
+            b"symlink" => {
+                self.expect(b"target")?;
+                let target = self.string()?;
+                self.expect(b")")?;
+                FileTree::symlink(ByteBuf::from(target))
+            }
+            b"directory" => {
+                let mut entries = HashMap::new();
+                loop {
+                    match &self.string()?[..] {
+                        b")" => break,
+                        b"entry" => {}
+                        other => bail!(ErrorKind::Format(format!(
+                            "expected 'entry' or ')', found '{}'",
+                            String::from_utf8_lossy(other)
+                        ))),
+                    }
+                    self.expect(b"(")?;
+                    self.expect(b"name")?;
+                    let name = self.string()?;
+                    if name.is_empty() || name.contains(&b'/') || name == b"." || name == b".." {
+                        bail!(ErrorKind::Format(format!(
+                            "invalid file name '{}'",
+                            String::from_utf8_lossy(&name)
+                        )));
+                    }
+                    self.expect(b"node")?;
+                    let node = self.node()?;
+                    self.expect(b")")?;
+
+                    let name = ByteBuf::from(name);
+                    if entries.contains_key(&name) {
+                        bail!(ErrorKind::Format(format!(
+                            "duplicate file name '{}'",
+                            String::from_utf8_lossy(&name)
+                        )));
+                    }
+                    entries.insert(name, node);
+                }
+                FileTree::directory(entries)
+            }
+            other => bail!(ErrorKind::Format(format!(
+                "unknown node type '{}'",
+                String::from_utf8_lossy(other)
+            ))),
+        };
+
+        Ok(tree)
+    }
+}
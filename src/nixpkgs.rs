@@ -1,18 +1,64 @@
-//! Read package information from nix-env.
+//! Read package information from nix-env or from a flake.
 //!
 //! This module implements the gathering of initial set of root store paths to fetch.
 //! We parse the output `nix-env --query` to figure out all accessible store paths with their attribute path
-//! and hashes.
+//! and hashes. For flakes, which `nix-env` does not support, we evaluate the packages with `nix eval --json`
+//! instead. Without any evaluation, the store paths can also be read from a file (see `read_paths`).
 use xml;
-use std::io::{self, Read};
+use serde_json;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Read};
 use xml::reader::{EventReader, XmlEvent};
 use xml::common::{TextPosition, Position};
 use std::process::{Command, Stdio, Child, ChildStdout};
 use std::fmt;
 use std::error;
 
+use hydra;
 use package::{PathOrigin, StorePath};
 
+/// The scopes of nixpkgs that are indexed in addition to the top-level packages by default.
+///
+/// These are package sets that only show up in `nix-env -qa -A someSet`. Some of them are not built
+/// directly by hydra. We still include them since parts of these sets may be built as dependencies
+/// of other packages that are built by hydra. This way, our attribute path information is more accurate.
+///
+/// We only need sets that are not marked "recurseIntoAttrs" here, since if they are, they are
+/// already part of the top-level packages.
+pub const DEFAULT_SCOPES: &[&str] = &[
+    "haskellPackages",
+    "rPackages",
+    "nodePackages",
+    "coqPackages",
+    "python3Packages",
+    "perlPackages",
+    "ocamlPackages",
+];
+
+/// Reads a list of scopes (attribute paths of package sets like `haskellPackages`), one per line.
+///
+/// Empty lines and lines starting with `#` are ignored.
+///
+/// # Example
+///
+/// ```
+/// use nix_index::nixpkgs::read_scopes;
+///
+/// let scopes = read_scopes("# extra package sets\nhaskellPackages\n\n  python3Packages  \n".as_bytes()).unwrap();
+/// assert_eq!(scopes, vec!["haskellPackages", "python3Packages"]);
+/// ```
+pub fn read_scopes<R: BufRead>(reader: R) -> io::Result<Vec<String>> {
+    let mut scopes = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            scopes.push(line.to_string());
+        }
+    }
+    Ok(scopes)
+}
+
 /// Calls `nix-env` to list the packages in the given nixpkgs.
 ///
 /// The `nixpkgs` argument can either be a path to a nixpkgs checkout or another expression
@@ -55,6 +101,222 @@ pub fn query_packages(
     }
 }
 
+/// The Nix expression that lists the out paths of the packages of a flake.
+///
+/// It is a function that takes the flake reference and the attribute path of a scope (a list of
+/// strings) and returns an attribute set that maps the attribute path of each package to an
+/// attribute set of its outputs and their store paths. Like `nix-env -qa`, it recurses into attribute
+/// sets that have `recurseForDerivations = true` and skips packages that fail to evaluate (for example,
+/// because they are broken or marked insecure).
+const FLAKE_PACKAGES_EXPR: &str = r#"
+flakeRef: scope:
+let
+  flake = builtins.getFlake flakeRef;
+  system = builtins.currentSystem;
+  packages = flake.legacyPackages.${system} or flake.packages.${system} or { };
+  join = prefix: name: if prefix == "" then name else "${prefix}.${name}";
+  outputsOf = drv:
+    builtins.listToAttrs (map (output: { name = output; value = drv.${output}.outPath; }) (drv.outputs or [ "out" ]));
+  collect = prefix: set: builtins.concatLists (map (name:
+    let
+      attr = join prefix name;
+      value = builtins.tryEval set.${name};
+      v = value.value;
+      outputs = builtins.tryEval (let o = outputsOf v; in builtins.deepSeq o o);
+    in
+      if !value.success || !builtins.isAttrs v then [ ]
+      else if v.type or null == "derivation" then
+        (if outputs.success then [ { name = attr; value = outputs.value; } ] else [ ])
+      else if v.recurseForDerivations or false then collect attr v
+      else [ ]
+  ) (builtins.attrNames set));
+in
+  builtins.listToAttrs (collect (builtins.concatStringsSep "." scope) (builtins.foldl' (set: name: set.${name}) packages scope))
+"#;
+
+/// Quotes a string so that it can be used as a string literal in a Nix expression.
+fn nix_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' | '$' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Calls `nix eval` to list the packages of the given flake.
+///
+/// The `flake` argument is a flake reference such as `github:NixOS/nixpkgs/nixos-23.05` or a path to a
+/// directory containing a `flake.nix`. The packages are taken from the `legacyPackages` output of the flake
+/// for the current system, or from its `packages` output if there is no `legacyPackages` output.
+///
+/// If scope is `Some(attr)`, only packages that are a member of `attr` are returned, like for `query_packages`.
+///
+/// The returned store paths are the same as those returned by `query_packages` for the corresponding nixpkgs.
+pub fn query_flake_packages(
+    flake: &str,
+    scope: Option<&str>,
+    show_trace: bool,
+) -> Result<Vec<StorePath>, Error> {
+    let scope = scope.map_or_else(Vec::new, |scope| scope.split('.').map(nix_string).collect());
+    let expr = format!("({}) {} [ {} ]", FLAKE_PACKAGES_EXPR, nix_string(flake), scope.join(" "));
+
+    let mut cmd = Command::new("nix");
+    cmd.arg("--extra-experimental-features")
+        .arg("nix-command flakes")
+        .arg("eval")
+        .arg("--json")
+        // builtins.getFlake and builtins.currentSystem are not available in pure evaluation mode
+        .arg("--impure")
+        .arg("--expr")
+        .arg(expr)
+        .stdin(Stdio::null());
+
+    if show_trace {
+        cmd.arg("--show-trace");
+    }
+
+    let result = cmd.output()?;
+    if !result.status.success() {
+        let message = String::from_utf8_lossy(&result.stderr);
+        return Err(Error::Command(match result.status.code() {
+            Some(c) => format!("nix eval failed with exit code {}:\n{}", c, message),
+            None => format!("nix eval failed with unknown exit code:\n{}", message),
+        }));
+    }
+
+    parse_json_paths(&result.stdout[..])
+}
+
+/// A package in a JSON file of store paths.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonPackage {
+    /// The outputs of the package and their store paths.
+    Outputs(BTreeMap<String, String>),
+    /// A package as output by `nix-env -qa --json --out-path`, which has more information besides the outputs.
+    Package { outputs: BTreeMap<String, String> },
+}
+
+/// Parses a JSON object that maps attribute paths to the outputs of the package and their store paths.
+///
+/// This is the format of the output of `nix eval` in `query_flake_packages`. The output of
+/// `nix-env -qa --json --out-path`, where the outputs are in an `outputs` key of each package, is
+/// accepted as well.
+///
+/// ```
+/// use nix_index::nixpkgs::parse_json_paths;
+///
+/// let json = r#"{
+///     "hello": { "out": "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.10" },
+///     "xorg.libX11": {
+///         "dev": "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-libX11-1.6.5-dev",
+///         "out": "/nix/store/cccccccccccccccccccccccccccccccc-libX11-1.6.5"
+///     }
+/// }"#;
+///
+/// let paths = parse_json_paths(json.as_bytes()).unwrap();
+/// assert_eq!(paths.len(), 3);
+/// assert_eq!(paths[1].origin().attr, "xorg.libX11");
+/// assert_eq!(paths[1].origin().output, "dev");
+/// assert!(paths[1].origin().toplevel);
+/// assert_eq!(paths[1].name(), "libX11-1.6.5-dev");
+///
+/// let json = r#"{
+///     "hello": {
+///         "name": "hello-2.10",
+///         "outputs": { "out": "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.10" },
+///         "system": "x86_64-linux"
+///     }
+/// }"#;
+/// assert_eq!(parse_json_paths(json.as_bytes()).unwrap()[0].origin().attr, "hello");
+///
+/// assert!(parse_json_paths(r#"{ "hello": { "out": "/nix/store/hello" } }"#.as_bytes()).is_err());
+/// ```
+pub fn parse_json_paths<R: Read>(reader: R) -> Result<Vec<StorePath>, Error> {
+    let packages: BTreeMap<String, JsonPackage> = serde_json::from_reader(reader)?;
+
+    let mut paths = Vec::new();
+    for (attr, package) in packages {
+        let outputs = match package {
+            JsonPackage::Outputs(outputs) | JsonPackage::Package { outputs } => outputs,
+        };
+        for (output, path) in outputs {
+            let origin = PathOrigin {
+                attr: attr.clone(),
+                output: output,
+                toplevel: true,
+            };
+            let store_path = StorePath::parse(origin, &path).ok_or(Error::InvalidStorePath(path))?;
+            paths.push(store_path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Reads the store paths to index from a file, without evaluating nixpkgs.
+///
+/// The file can either be a JSON object in one of the formats accepted by `parse_json_paths`, or
+/// a list of store paths with one path per line, like the `store-paths.xz` file of a channel. Since
+/// such a list has no attribute paths, each store path is named after itself and is not marked as
+/// top-level (see `PathOrigin::from_name`).
+/// The file may be compressed with xz, zstd or bzip2.
+///
+/// # Example
+///
+/// ```
+/// use nix_index::nixpkgs::read_paths;
+///
+/// let list = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.10\n\n/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-glibc-2.27-bin\n";
+/// let paths = read_paths(list.as_bytes()).unwrap();
+/// assert_eq!(paths.len(), 2);
+/// assert_eq!(paths[0].origin().attr, "hello-2.10");
+/// assert_eq!(paths[0].origin().output, "out");
+/// assert!(!paths[0].origin().toplevel);
+/// assert_eq!(paths[1].origin().attr, "glibc-2.27");
+/// assert_eq!(paths[1].origin().output, "bin");
+///
+/// let json = r#"{ "hello": { "out": "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.10" } }"#;
+/// assert_eq!(read_paths(json.as_bytes()).unwrap()[0].origin().attr, "hello");
+///
+/// assert!(read_paths("hello\n".as_bytes()).is_err());
+/// ```
+pub fn read_paths<R: Read>(mut reader: R) -> Result<Vec<StorePath>, Error> {
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents)?;
+    let contents = hydra::decompress(contents)?;
+
+    if contents.iter().find(|c| !c.is_ascii_whitespace()) == Some(&b'{') {
+        return parse_json_paths(&contents[..]);
+    }
+
+    let mut paths = Vec::new();
+    for line in contents.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || Error::InvalidStorePath(line.to_string());
+        let dummy = PathOrigin {
+            attr: String::new(),
+            output: String::new(),
+            toplevel: true,
+        };
+        let origin = PathOrigin::from_name(&StorePath::parse(dummy, line).ok_or_else(invalid)?.name());
+        paths.push(StorePath::parse(origin, line).ok_or_else(invalid)?);
+    }
+    Ok(paths)
+}
+
 /// An iterator that parses the output of nix-env and returns parsed store paths.
 ///
 /// Use `query_packages` to create a value of this type.
@@ -390,7 +652,14 @@ pub enum Error {
     /// An IO error occurred
     Io(io::Error),
 
-    /// nix-env failed with an error message
+    /// Parsing the JSON output of `nix eval` failed
+    Json(serde_json::Error),
+
+    /// A store path in the JSON output of `nix eval` or in a file of store paths does not match the format
+    /// `$(STOREDIR)$(HASH)-$(NAME)`
+    InvalidStorePath(String),
+
+    /// nix-env or nix failed with an error message
     Command(String),
 }
 
@@ -399,6 +668,8 @@ impl error::Error for Error {
         match *self {
             Error::Parse(_) => "nix-env output parse error",
             Error::Io(_) => "io error",
+            Error::Json(_) => "nix eval output parse error",
+            Error::InvalidStorePath(_) => "invalid store path",
             Error::Command(_) => "nix-env error",
         }
     }
@@ -410,7 +681,9 @@ impl fmt::Display for Error {
         match *self {
             Parse(ref e) => write!(f, "parsing XML output of nix-env failed: {}", e),
             Io(ref e) => write!(f, "IO error: {}", e),
-            Command(ref e) => write!(f, "nix-env failed with error: {}", e),
+            Json(ref e) => write!(f, "parsing JSON output of nix eval failed: {}", e),
+            InvalidStorePath(ref path) => write!(f, "store path does not match expected format /prefix/hash-name: {}", path),
+            Command(ref e) => write!(f, "{}", e),
         }
     }
 }
@@ -427,3 +700,9 @@ impl From<ParserError> for Error {
         Error::Parse(err)
     }
 }
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::Json(err)
+    }
+}
@@ -40,7 +40,56 @@ pub struct PathOrigin {
     pub toplevel: bool,
 }
 
+/// The outputs that `PathOrigin::from_name` splits off the end of store path names.
+const KNOWN_OUTPUTS: &[&str] = &["bin", "dev", "lib", "man", "doc", "devdoc", "devman", "info", "static", "debug"];
+
 impl PathOrigin {
+    /// Returns the origin for a store path of which we only know the name, such as a path from
+    /// a channel's `store-paths.xz` or from the local store.
+    ///
+    /// Such a path cannot be mapped to an attribute of nixpkgs, so its name is used as `attr`
+    /// instead. If the name ends with a common output name after the version (as in
+    /// `openssl-3.0.7-dev`), that output is split off; otherwise the output is `out`.
+    /// The origin is not marked as `toplevel`, since `attr` is not an attribute that could be
+    /// installed. This way, `nix-locate --top-level` and command-not-found do not suggest it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use nix_index::package::PathOrigin;
+    ///
+    /// let origin = PathOrigin::from_name("openssl-3.0.7-dev");
+    /// assert_eq!(origin.attr, "openssl-3.0.7");
+    /// assert_eq!(origin.output, "dev");
+    /// assert!(!origin.toplevel);
+    ///
+    /// assert_eq!(PathOrigin::from_name("hello-2.10").output, "out");
+    /// // without a version, the suffix is part of the package name
+    /// assert_eq!(PathOrigin::from_name("gnome-doc").attr, "gnome-doc");
+    /// assert_eq!(PathOrigin::from_name("gnome-doc").output, "out");
+    /// ```
+    pub fn from_name(name: &str) -> PathOrigin {
+        // like `builtins.parseDrvName`, the version starts at the first component that does not
+        // start with a letter
+        let has_version = |name: &str| {
+            name.split('-')
+                .skip(1)
+                .any(|c| c.chars().next().map_or(false, |c| !c.is_alphabetic()))
+        };
+
+        let (attr, output) = match name.rfind('-') {
+            Some(i) if KNOWN_OUTPUTS.contains(&&name[i + 1..]) && has_version(&name[..i]) => {
+                (&name[..i], &name[i + 1..])
+            }
+            _ => (name, "out"),
+        };
+        PathOrigin {
+            attr: attr.to_string(),
+            output: output.to_string(),
+            toplevel: false,
+        }
+    }
+
     /// Encodes a path origin as a sequence of bytes, such that it can be decoed using `decode`.
     ///
     /// The encoding does not use the bytes `0x00` nor `0x01`, as long as neither `attr` nor `output`
@@ -129,6 +178,12 @@ pub struct StorePath {
     hash: String,
     name: String,
     origin: PathOrigin,
+    /// The URL of the binary cache that this store path was fetched from, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    /// Whether the narinfo of this store path had a valid signature by a trusted key, if checked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    verified: Option<bool>,
 }
 
 impl StorePath {
@@ -153,6 +208,8 @@ impl StorePath {
                         hash: hash.to_string(),
                         name: name.to_string(),
                         origin: origin,
+                        source: None,
+                        verified: None,
                     }
                 })
             })
@@ -271,4 +328,43 @@ impl StorePath {
     pub fn origin(&self) -> Cow<PathOrigin> {
         Cow::Borrowed(&self.origin)
     }
+
+    /// Returns the URL of the binary cache that this store path was fetched from.
+    ///
+    /// This is `None` if the store path did not come from a binary cache, or if the database
+    /// was created by an older version of nix-index that did not record this information.
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_ref().map(|s| s.as_str())
+    }
+
+    /// Sets the URL of the binary cache that this store path was fetched from.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use nix_index::package::{PathOrigin, StorePath};
+    ///
+    /// let origin = PathOrigin { attr: "dummy".to_string(), output: "out".to_string(), toplevel: true };
+    /// let store_path = StorePath::parse(origin, "/nix/store/010yd8jls8w4vcnql4zhjbnyp2yay5pl-bash-4.4-p5").unwrap();
+    /// assert_eq!(store_path.source(), None);
+    ///
+    /// let store_path = store_path.with_source(Some("https://cache.nixos.org".to_string()));
+    /// assert_eq!(store_path.source(), Some("https://cache.nixos.org"));
+    /// ```
+    pub fn with_source(self, source: Option<String>) -> StorePath {
+        StorePath { source: source, ..self }
+    }
+
+    /// Returns whether the signature of this store path was verified when it was fetched.
+    ///
+    /// This is `None` if signatures were not checked. Otherwise, `Some(false)` means that the
+    /// narinfo had no valid signature by any of the trusted keys.
+    pub fn verified(&self) -> Option<bool> {
+        self.verified
+    }
+
+    /// Records whether the signature of this store path was verified.
+    pub fn with_verified(self, verified: Option<bool>) -> StorePath {
+        StorePath { verified: verified, ..self }
+    }
 }
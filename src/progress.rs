@@ -0,0 +1,194 @@
+//! Progress reporting for indexing runs.
+//!
+//! While `nix-index` builds a database, it reports its progress as a sequence of `Event`s to an
+//! implementation of the `Progress` trait. Applications that embed the indexing can implement the
+//! trait to render their own progress bars. This module provides two implementations:
+//!
+//! * `TextProgress` writes the human-readable progress output of `nix-index`, which updates a single
+//!   line in place while fetching.
+//! * `JsonProgress` writes one JSON object per event and line (`nix-index --progress=json`), which
+//!   is suitable for CI logs and for other programs. Each object has an `event` key with the type of
+//!   the event; the other keys are the fields of the corresponding `Event` variant.
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use separator::Separatable;
+use serde_json;
+
+use report::{Problem, Stage};
+
+/// The phases of an indexing run, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Querying the store paths to index, for example by evaluating nixpkgs.
+    QueryPackages,
+    /// Fetching the file listings of the store paths and their references.
+    Fetch,
+    /// Writing the database after all file listings were fetched.
+    WriteDatabase,
+}
+
+/// Something that happened during an indexing run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A new phase started.
+    Phase { phase: Phase },
+    /// The counts of processed store paths changed.
+    Progress {
+        /// The number of store paths that were indexed.
+        indexed: u64,
+        /// The number of store paths without a file listing, including those that failed.
+        missing: u64,
+        /// The number of store paths that failed to fetch or parse.
+        failed: u64,
+        /// The number of store paths that still need processing.
+        queue: usize,
+        /// The number of requests sent to binary caches so far.
+        requests: u64,
+        /// The number of bytes received from binary caches so far.
+        bytes_fetched: u64,
+    },
+    /// A store path could not be indexed because of an error.
+    Failed(Problem),
+    /// An informational message.
+    Message { message: String },
+    /// A warning that does not stop the run.
+    Warning { message: String },
+    /// The database was written.
+    Finished {
+        /// The size of the database, in bytes.
+        index_bytes: u64,
+    },
+}
+
+/// Receives the progress events of an indexing run.
+pub trait Progress {
+    /// Called for every event, in the order in which they happen.
+    fn event(&mut self, event: &Event);
+}
+
+/// Writes the human-readable progress output of `nix-index`.
+///
+/// Progress events overwrite the current line, so the output should go to a terminal.
+pub struct TextProgress<W: Write> {
+    output: W,
+}
+
+impl<W: Write> TextProgress<W> {
+    /// Creates a new progress writer that writes to `output`.
+    pub fn new(output: W) -> TextProgress<W> {
+        TextProgress { output: output }
+    }
+
+    fn write(&mut self, event: &Event) -> io::Result<()> {
+        match *event {
+            Event::Phase { phase: Phase::QueryPackages } => writeln!(self.output, "+ querying available packages")?,
+            Event::Phase { phase: Phase::Fetch } => write!(self.output, "+ generating index\r")?,
+            // ends the line of the progress output
+            Event::Phase { phase: Phase::WriteDatabase } => writeln!(self.output)?,
+            Event::Progress { indexed, missing, queue, .. } => write!(
+                self.output,
+                "+ generating index: {:05} paths found :: {:05} paths not in binary cache :: {:05} paths in queue \r",
+                indexed, missing, queue
+            )?,
+            Event::Failed(ref problem) => {
+                let what = match problem.stage {
+                    Stage::Narinfo => "references",
+                    Stage::Listing => "file listing",
+                };
+                writeln!(self.output, "\nError: fetching the {} of store path '{}' failed", what, problem.store_path)?;
+                if let Some(ref error) = problem.error {
+                    writeln!(self.output, "Caused by: {}", error)?;
+                }
+            }
+            Event::Message { ref message } => writeln!(self.output, "+ {}", message)?,
+            Event::Warning { ref message } => writeln!(self.output, "warning: {}", message)?,
+            Event::Finished { index_bytes } => {
+                writeln!(self.output, "+ wrote index of {} bytes", index_bytes.separated_string())?
+            }
+        }
+        self.output.flush()
+    }
+}
+
+impl<W: Write> Progress for TextProgress<W> {
+    fn event(&mut self, event: &Event) {
+        // progress output is best effort, a closed stderr should not abort the run
+        self.write(event).unwrap_or(());
+    }
+}
+
+/// The minimum time between two `Event::Progress` lines written by a `JsonProgress`.
+const JSON_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Writes each event as a JSON object on its own line.
+///
+/// Since the counts change for every store path, `Event::Progress` events are written at most once
+/// per second. The latest counts are always written before any other event, so the last progress
+/// line of a phase has the final counts.
+///
+/// # Example
+///
+/// ```
+/// use nix_index::progress::{Event, JsonProgress, Phase, Progress};
+///
+/// let mut output = Vec::new();
+/// {
+///     let mut progress = JsonProgress::new(&mut output);
+///     progress.event(&Event::Phase { phase: Phase::Fetch });
+///     for indexed in 1..1000 {
+///         progress.event(&Event::Progress { indexed: indexed, missing: 0, failed: 0, queue: 1000 - indexed as usize, requests: 0, bytes_fetched: 0 });
+///     }
+///     progress.event(&Event::Phase { phase: Phase::WriteDatabase });
+/// }
+///
+/// let output = String::from_utf8(output).unwrap();
+/// let lines = output.lines().collect::<Vec<_>>();
+/// assert_eq!(lines.len(), 4);
+/// assert_eq!(lines[0], r#"{"event":"phase","phase":"fetch"}"#);
+/// assert!(lines[2].contains(r#""indexed":999"#));
+/// assert_eq!(lines[3], r#"{"event":"phase","phase":"write_database"}"#);
+/// ```
+pub struct JsonProgress<W: Write> {
+    output: W,
+    /// When the last progress event was written.
+    last_progress: Option<Instant>,
+    /// A progress event that was not written yet, because the previous one was written too recently.
+    pending: Option<Event>,
+}
+
+impl<W: Write> JsonProgress<W> {
+    /// Creates a new progress writer that writes to `output`.
+    pub fn new(output: W) -> JsonProgress<W> {
+        JsonProgress {
+            output: output,
+            last_progress: None,
+            pending: None,
+        }
+    }
+
+    fn write(&mut self, event: &Event) -> io::Result<()> {
+        serde_json::to_writer(&mut self.output, event)?;
+        writeln!(self.output)?;
+        self.output.flush()
+    }
+}
+
+impl<W: Write> Progress for JsonProgress<W> {
+    fn event(&mut self, event: &Event) {
+        if let Event::Progress { .. } = *event {
+            if self.last_progress.map_or(false, |last| last.elapsed() < JSON_PROGRESS_INTERVAL) {
+                self.pending = Some(event.clone());
+                return;
+            }
+            self.pending = None;
+            self.last_progress = Some(Instant::now());
+        } else if let Some(pending) = self.pending.take() {
+            self.write(&pending).unwrap_or(());
+        }
+
+        // progress output is best effort, a closed stderr should not abort the run
+        self.write(event).unwrap_or(());
+    }
+}
@@ -0,0 +1,217 @@
+//! A structured report about an indexing run.
+//!
+//! The progress output of `nix-index` only shows counts, so it does not tell which store paths are
+//! missing from the index and why. The report records every store path that could not be indexed,
+//! together with the attribute it belongs to and the error, as well as timing and byte totals. It is
+//! written as JSON by `nix-index --report FILE`, which makes it possible to explain coverage gaps and
+//! to alert when the failure rate of a run is unusually high.
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use package::StorePath;
+
+/// Why a store path could not be indexed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// The binary caches do not have the narinfo or the file listing of the store path.
+    Missing,
+    /// Fetching the narinfo or the file listing failed, for example because of an HTTP error or a timeout.
+    FetchFailed,
+    /// The narinfo or the file listing was fetched, but could not be decoded or parsed.
+    ParseFailed,
+}
+
+/// The step of indexing a store path at which a problem occurred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Fetching the narinfo, which contains the references of the store path.
+    Narinfo,
+    /// Fetching or reading the file listing.
+    Listing,
+}
+
+/// A store path that could not be indexed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Problem {
+    /// The full store path.
+    pub store_path: String,
+    /// The attribute of nixpkgs that the store path belongs to (see `PathOrigin::attr`).
+    pub attr: String,
+    /// The output of the attribute.
+    pub output: String,
+    /// Whether the store path is a top-level attribute or only a dependency of one.
+    pub toplevel: bool,
+    pub status: Status,
+    pub stage: Stage,
+    /// The error message, with its causes separated by `: `. There is no error for missing paths.
+    pub error: Option<String>,
+}
+
+/// A scope of nixpkgs (such as `haskellPackages`) that could not be evaluated, so its packages are not indexed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScopeFailure {
+    /// The attribute path of the scope.
+    pub scope: String,
+    /// The error message of the evaluation.
+    pub error: String,
+}
+
+/// The report of an indexing run.
+///
+/// # Example
+///
+/// ```
+/// extern crate nix_index;
+/// extern crate serde_json;
+///
+/// use nix_index::package::{PathOrigin, StorePath};
+/// use nix_index::report::{Report, Stage, Status};
+///
+/// # fn main() {
+/// let origin = PathOrigin { attr: "hello".to_string(), output: "out".to_string(), toplevel: true };
+/// let path = StorePath::parse(origin, "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.10").unwrap();
+///
+/// let mut report = Report::new();
+/// report.add_indexed();
+/// report.add_indexed();
+/// report.add_indexed();
+/// report.add_problem(&path, Status::FetchFailed, Stage::Listing, Some("request timed out".to_string()));
+/// report.finish();
+///
+/// assert_eq!(report.failure_rate, 0.25);
+/// let json = serde_json::to_value(&report).unwrap();
+/// assert_eq!(json["problems"][0]["attr"], "hello");
+/// assert_eq!(json["problems"][0]["status"], "fetch_failed");
+/// assert_eq!(json["fetch_failed"], 1);
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    /// When the run started, in seconds since the Unix epoch.
+    pub started_at: u64,
+    /// The duration of the whole run, in seconds.
+    pub duration_secs: f64,
+    /// The time spent querying the store paths to index (for example, evaluating nixpkgs), in seconds.
+    pub query_secs: f64,
+    /// The time spent fetching file listings, in seconds.
+    pub fetch_secs: f64,
+    /// The time spent writing the database after all file listings were fetched, in seconds.
+    pub write_secs: f64,
+    /// The number of store paths that were indexed.
+    pub indexed: u64,
+    /// The number of store paths that are missing from the binary caches.
+    pub missing: u64,
+    /// The number of store paths for which a request failed.
+    pub fetch_failed: u64,
+    /// The number of store paths for which a response could not be parsed.
+    pub parse_failed: u64,
+    /// The fraction of store paths that failed to fetch or parse, out of all processed store paths.
+    pub failure_rate: f64,
+    /// The number of requests sent to binary caches, including retries.
+    pub requests: u64,
+    /// The number of bytes received from binary caches, before decompression.
+    pub bytes_fetched: u64,
+    /// The size of the written database, in bytes.
+    pub index_bytes: u64,
+    /// All store paths that could not be indexed, in the order in which they were processed.
+    pub problems: Vec<Problem>,
+    /// The scopes of nixpkgs that could not be evaluated.
+    pub failed_scopes: Vec<ScopeFailure>,
+    #[serde(skip)]
+    started: Instant,
+}
+
+/// Converts a duration to seconds.
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+impl Report {
+    /// Starts the report of a run that starts now.
+    pub fn new() -> Report {
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        Report {
+            started_at: started_at,
+            duration_secs: 0.0,
+            query_secs: 0.0,
+            fetch_secs: 0.0,
+            write_secs: 0.0,
+            indexed: 0,
+            missing: 0,
+            fetch_failed: 0,
+            parse_failed: 0,
+            failure_rate: 0.0,
+            requests: 0,
+            bytes_fetched: 0,
+            index_bytes: 0,
+            problems: Vec::new(),
+            failed_scopes: Vec::new(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Returns the time since the start of the run.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Records a store path that was indexed.
+    pub fn add_indexed(&mut self) {
+        self.indexed += 1;
+    }
+
+    /// Records a store path that could not be indexed and returns the recorded problem.
+    pub fn add_problem(&mut self, path: &StorePath, status: Status, stage: Stage, error: Option<String>) -> &Problem {
+        match status {
+            Status::Missing => self.missing += 1,
+            Status::FetchFailed => self.fetch_failed += 1,
+            Status::ParseFailed => self.parse_failed += 1,
+        }
+        let origin = path.origin();
+        self.problems.push(Problem {
+            store_path: path.as_str().into_owned(),
+            attr: origin.attr.clone(),
+            output: origin.output.clone(),
+            toplevel: origin.toplevel,
+            status: status,
+            stage: stage,
+            error: error,
+        });
+        &self.problems[self.problems.len() - 1]
+    }
+
+    /// Records a scope that could not be evaluated and returns the recorded failure.
+    pub fn add_failed_scope(&mut self, scope: &str, error: String) -> &ScopeFailure {
+        self.failed_scopes.push(ScopeFailure {
+            scope: scope.to_string(),
+            error: error,
+        });
+        &self.failed_scopes[self.failed_scopes.len() - 1]
+    }
+
+    /// Records the end of the phase that queries the store paths to index.
+    pub fn finish_query(&mut self) {
+        self.query_secs = secs(self.elapsed());
+    }
+
+    /// Records the end of the phase that fetches the file listings.
+    pub fn finish_fetch(&mut self) {
+        self.fetch_secs = secs(self.elapsed()) - self.query_secs;
+    }
+
+    /// Records the end of the run and computes the totals.
+    pub fn finish(&mut self) {
+        self.duration_secs = secs(self.elapsed());
+        self.write_secs = self.duration_secs - self.query_secs - self.fetch_secs;
+        let failed = self.fetch_failed + self.parse_failed;
+        let total = self.indexed + self.missing + failed;
+        self.failure_rate = if total == 0 { 0.0 } else { failed as f64 / total as f64 };
+    }
+}
+
+impl Default for Report {
+    fn default() -> Report {
+        Report::new()
+    }
+}
@@ -0,0 +1,142 @@
+//! Verifying the signatures of narinfo files.
+//!
+//! Binary caches sign each store path with an ed25519 key. The signed message is a *fingerprint*
+//! of the store path, which covers the store path itself, the hash and size of its NAR and its
+//! references (see `fingerprint`). The narinfo contains the signatures in `Sig:` lines, each of
+//! the form `key-name:base64-signature`.
+//!
+//! The trusted public keys use the same format as Nix's `trusted-public-keys` option:
+//! `key-name:base64-public-key`, for example `cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=`.
+use std::fmt;
+use std::str::FromStr;
+use base64;
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+error_chain! {
+    errors {
+        ParsePublicKey(key: String) {
+            description("public key parse error")
+            display("invalid public key '{}', expected name:base64-encoded ed25519 key", key)
+        }
+        ParsePolicy(policy: String) {
+            description("signature policy parse error")
+            display("invalid signature policy '{}', expected one of drop, warn or mark", policy)
+        }
+    }
+}
+
+/// A named ed25519 public key that is trusted to sign store paths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey {
+    /// The name of the key, which is used to find the matching signature.
+    pub name: String,
+    /// The raw bytes of the key.
+    pub key: Vec<u8>,
+}
+
+impl FromStr for PublicKey {
+    type Err = Error;
+
+    /// Parses a public key in the format used by Nix.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use nix_index::signing::PublicKey;
+    ///
+    /// let key: PublicKey = "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=".parse().unwrap();
+    /// assert_eq!(key.name, "cache.nixos.org-1");
+    /// assert_eq!(key.key.len(), 32);
+    ///
+    /// assert!("cache.nixos.org-1".parse::<PublicKey>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<PublicKey> {
+        let mut parts = s.trim().splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let key = parts
+            .next()
+            .and_then(|key| base64::decode(key).ok())
+            .filter(|key| key.len() == 32 && !name.is_empty())
+            .ok_or_else(|| ErrorKind::ParsePublicKey(s.to_string()))?;
+
+        Ok(PublicKey {
+            name: name.to_string(),
+            key: key,
+        })
+    }
+}
+
+/// What to do with store paths that do not have a valid signature from any trusted key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignaturePolicy {
+    /// Leave the store path out of the index, as if it was not available in the binary cache.
+    Drop,
+    /// Print a warning, but index the store path like any other path.
+    Warn,
+    /// Index the store path, but record that it is not verified (see `StorePath::verified`).
+    Mark,
+}
+
+impl FromStr for SignaturePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SignaturePolicy> {
+        match s {
+            "drop" => Ok(SignaturePolicy::Drop),
+            "warn" => Ok(SignaturePolicy::Warn),
+            "mark" => Ok(SignaturePolicy::Mark),
+            _ => Err(ErrorKind::ParsePolicy(s.to_string()).into()),
+        }
+    }
+}
+
+impl fmt::Display for SignaturePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            SignaturePolicy::Drop => "drop",
+            SignaturePolicy::Warn => "warn",
+            SignaturePolicy::Mark => "mark",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Computes the fingerprint of a store path, which is the message that binary caches sign.
+///
+/// All paths must be full store paths, including the store directory.
+///
+/// # Example
+///
+/// ```
+/// use nix_index::signing::fingerprint;
+///
+/// let references = vec!["/nix/store/2q3hcvqpqcbkryiqb4wg8qdyh6dh8q4c-glibc-2.27".to_string()];
+/// assert_eq!(
+///     fingerprint("/nix/store/bdjyhh70npndlq3rzmggh4f2dzdsj4xy-hello-2.10", "sha256:1m8d", 205424, &references),
+///     "1;/nix/store/bdjyhh70npndlq3rzmggh4f2dzdsj4xy-hello-2.10;sha256:1m8d;205424;/nix/store/2q3hcvqpqcbkryiqb4wg8qdyh6dh8q4c-glibc-2.27",
+/// );
+/// ```
+pub fn fingerprint(store_path: &str, nar_hash: &str, nar_size: u64, references: &[String]) -> String {
+    format!("1;{};{};{};{}", store_path, nar_hash, nar_size, references.join(","))
+}
+
+/// Returns true if any of the given signatures is a valid signature of `fingerprint` by one of the trusted keys.
+///
+/// Signatures have the form `key-name:base64-signature`. Signatures by unknown keys and malformed
+/// signatures are ignored.
+pub fn verify<S: AsRef<str>>(trusted: &[PublicKey], fingerprint: &str, signatures: &[S]) -> bool {
+    signatures.iter().any(|sig| {
+        let mut parts = sig.as_ref().trim().splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let sig = match parts.next().and_then(|sig| base64::decode(sig).ok()) {
+            Some(sig) => sig,
+            None => return false,
+        };
+
+        trusted.iter().filter(|key| key.name == name).any(|key| {
+            UnparsedPublicKey::new(&ED25519, &key.key)
+                .verify(fingerprint.as_bytes(), &sig)
+                .is_ok()
+        })
+    })
+}
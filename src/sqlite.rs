@@ -0,0 +1,258 @@
+//! Exporting a nix-index database to SQLite.
+//!
+//! The native database format is optimized for searching file paths with a pattern, but it is not
+//! very convenient for other kinds of analysis. This module converts a database into a normalized
+//! SQLite database, so that arbitrary SQL queries can be run against the index.
+//!
+//! The generated database has the following schema:
+//!
+//! ```text
+//! metadata (key TEXT PRIMARY KEY, value TEXT)
+//! origins  (id INTEGER PRIMARY KEY, attr TEXT, output TEXT, toplevel INTEGER)
+//! packages (id INTEGER PRIMARY KEY, hash TEXT, name TEXT, store_dir TEXT, path TEXT, origin_id INTEGER, source TEXT, verified INTEGER)
+//! files    (package_id INTEGER, path TEXT, raw_path BLOB, type TEXT, size INTEGER, executable INTEGER, target TEXT, raw_target BLOB)
+//! ```
+//!
+//! The `type` column of `files` is one of `regular`, `directory` or `symlink`. As in `FileNode`,
+//! the `size` of a directory is the number of entries it contains, and the size of a symlink is zero.
+//! File paths start with a `/` and are relative to the root of the package (the root itself has the empty path).
+//! File paths and symlink targets are always stored as `TEXT`, with invalid UTF-8 replaced by U+FFFD, so
+//! that they can be compared with other strings. For the rare paths and targets that are not valid UTF-8,
+//! the original bytes are stored in `raw_path` and `raw_target` as `BLOB`, which are `NULL` otherwise.
+//! The `source` of a package is the URL of the binary cache it was fetched from, or `NULL` if unknown.
+//! `verified` records whether the package had a valid signature by a trusted key, or is `NULL` if this was not checked.
+//!
+//! Note that the nix-index database does not record the references of store paths, so there is no
+//! table for references.
+use std::collections::HashMap;
+use std::path::Path;
+use rusqlite::{self, Connection};
+
+use database;
+use files::{FileNode, FileTreeEntry};
+use package::PathOrigin;
+
+error_chain! {
+    errors {
+        OutputExists(path: String) {
+            description("output file already exists")
+            display("the file '{}' already exists, refusing to overwrite it", path)
+        }
+    }
+
+    links {
+        Database(database::Error, database::ErrorKind);
+    }
+
+    foreign_links {
+        Sqlite(rusqlite::Error);
+    }
+}
+
+/// The statements that create the tables of the exported database.
+const SCHEMA: &'static str = "
+    PRAGMA journal_mode = OFF;
+    PRAGMA synchronous = OFF;
+    CREATE TABLE metadata (
+        key TEXT PRIMARY KEY NOT NULL,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE origins (
+        id INTEGER PRIMARY KEY,
+        attr TEXT NOT NULL,
+        output TEXT NOT NULL,
+        toplevel INTEGER NOT NULL
+    );
+    CREATE TABLE packages (
+        id INTEGER PRIMARY KEY,
+        hash TEXT NOT NULL,
+        name TEXT NOT NULL,
+        store_dir TEXT NOT NULL,
+        path TEXT NOT NULL,
+        origin_id INTEGER NOT NULL REFERENCES origins(id),
+        source TEXT,
+        verified INTEGER
+    );
+    CREATE TABLE files (
+        package_id INTEGER NOT NULL REFERENCES packages(id),
+        path TEXT NOT NULL,
+        raw_path BLOB,
+        type TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        executable INTEGER NOT NULL,
+        target TEXT,
+        raw_target BLOB
+    );
+";
+
+/// The statements that create the indexes of the exported database.
+///
+/// The indexes are only created after all rows have been inserted, since that is a lot faster than
+/// updating them for every insert.
+const INDEXES: &'static str = "
+    CREATE UNIQUE INDEX origins_attr_output ON origins(attr, output, toplevel);
+    CREATE INDEX packages_hash ON packages(hash);
+    CREATE INDEX packages_name ON packages(name);
+    CREATE INDEX packages_origin ON packages(origin_id);
+    CREATE INDEX files_package ON files(package_id);
+    CREATE INDEX files_path ON files(path);
+";
+
+/// The number of rows that were written by `export`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Summary {
+    /// The number of packages that were exported.
+    pub packages: u64,
+    /// The number of file entries that were exported, over all packages.
+    pub files: u64,
+}
+
+/// Converts bytes to a string, together with the original bytes if they are not valid UTF-8.
+fn text_and_raw(bytes: Vec<u8>) -> (String, Option<Vec<u8>>) {
+    match String::from_utf8(bytes) {
+        Ok(s) => (s, None),
+        Err(e) => (String::from_utf8_lossy(e.as_bytes()).into_owned(), Some(e.into_bytes())),
+    }
+}
+
+/// Exports all packages produced by `packages` into a new SQLite database at `path`.
+///
+/// The target file must not exist yet. The `metadata` pairs are stored in the `metadata` table
+/// of the generated database.
+///
+/// # Example
+///
+/// ```
+/// extern crate nix_index;
+/// extern crate rusqlite;
+/// extern crate serde_bytes;
+///
+/// use std::collections::HashMap;
+/// use std::env;
+/// use std::fs;
+/// use rusqlite::Connection;
+/// use serde_bytes::ByteBuf;
+/// use nix_index::database::{Reader, Writer};
+/// use nix_index::files::FileTree;
+/// use nix_index::package::{PathOrigin, StorePath};
+/// use nix_index::sqlite;
+///
+/// # fn main() {
+/// let dir = env::temp_dir().join(format!("nix-index-sqlite-doctest-{}", std::process::id()));
+/// fs::create_dir_all(&dir).unwrap();
+///
+/// let origin = PathOrigin { attr: "hello".to_string(), output: "out".to_string(), toplevel: true };
+/// let path = StorePath::parse(origin, "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.10").unwrap();
+/// let mut contents = HashMap::new();
+/// contents.insert(ByteBuf::from(b"caf\xe9".to_vec()), FileTree::regular(3, false));
+/// let mut db = Writer::create(dir.join("files"), 1).unwrap();
+/// db.add(path, FileTree::directory(contents)).unwrap();
+/// db.finish().unwrap();
+///
+/// let output = dir.join("index.sqlite");
+/// let summary = sqlite::export(Reader::open(dir.join("files")).unwrap().packages(), &output, &[]).unwrap();
+/// assert_eq!(summary.files, 2);
+///
+/// let conn = Connection::open(&output).unwrap();
+/// let (path, raw_path): (String, Option<Vec<u8>>) = conn
+///     .query_row("SELECT path, raw_path FROM files WHERE type = 'regular'", rusqlite::NO_PARAMS, |row| {
+///         Ok((row.get(0)?, row.get(1)?))
+///     })
+///     .unwrap();
+/// assert_eq!(path, "/caf\u{FFFD}");
+/// assert_eq!(raw_path, Some(b"/caf\xe9".to_vec()));
+///
+/// fs::remove_dir_all(&dir).unwrap();
+/// # }
+/// ```
+pub fn export<P: AsRef<Path>>(
+    packages: database::PackageIter,
+    path: P,
+    metadata: &[(&str, String)],
+) -> Result<Summary> {
+    let path = path.as_ref();
+    if path.exists() {
+        bail!(ErrorKind::OutputExists(path.to_string_lossy().into_owned()));
+    }
+
+    let mut conn = Connection::open(path)?;
+    conn.execute_batch(SCHEMA)?;
+
+    let mut summary = Summary::default();
+    {
+        let tx = conn.transaction()?;
+        {
+            let mut insert_meta = tx.prepare("INSERT INTO metadata (key, value) VALUES (?1, ?2)")?;
+            let mut insert_origin = tx.prepare(
+                "INSERT INTO origins (attr, output, toplevel) VALUES (?1, ?2, ?3)",
+            )?;
+            let mut insert_package = tx.prepare(
+                "INSERT INTO packages (hash, name, store_dir, path, origin_id, source, verified) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            let mut insert_file = tx.prepare(
+                "INSERT INTO files (package_id, path, raw_path, type, size, executable, target, raw_target) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?;
+
+            for &(key, ref value) in metadata {
+                insert_meta.execute(params![key, value])?;
+            }
+
+            let mut origins: HashMap<PathOrigin, i64> = HashMap::new();
+            for package in packages {
+                let (store_path, entries) = package?;
+
+                let origin = store_path.origin().into_owned();
+                let origin_id = match origins.get(&origin) {
+                    Some(id) => *id,
+                    None => {
+                        insert_origin.execute(params![origin.attr, origin.output, origin.toplevel])?;
+                        tx.last_insert_rowid()
+                    }
+                };
+                origins.insert(origin, origin_id);
+
+                insert_package.execute(params![
+                    store_path.hash().as_ref(),
+                    store_path.name().as_ref(),
+                    store_path.store_dir().as_ref(),
+                    store_path.as_str().as_ref(),
+                    origin_id,
+                    store_path.source(),
+                    store_path.verified(),
+                ])?;
+                let package_id = tx.last_insert_rowid();
+
+                for FileTreeEntry { path, node } in entries {
+                    let (typ, size, executable, target) = match node {
+                        FileNode::Regular { size, executable } => ("regular", size, executable, None),
+                        FileNode::Directory { size, .. } => ("directory", size, false, None),
+                        FileNode::Symlink { target } => {
+                            ("symlink", 0, false, Some(text_and_raw(Vec::from(target))))
+                        }
+                    };
+                    let (path, raw_path) = text_and_raw(path);
+                    let (target, raw_target) = match target {
+                        Some((target, raw_target)) => (Some(target), raw_target),
+                        None => (None, None),
+                    };
+                    insert_file.execute(params![
+                        package_id,
+                        path,
+                        raw_path,
+                        typ,
+                        size as i64,
+                        executable,
+                        target,
+                        raw_target,
+                    ])?;
+                    summary.files += 1;
+                }
+                summary.packages += 1;
+            }
+        }
+        tx.commit()?;
+    }
+
+    conn.execute_batch(INDEXES)?;
+    Ok(summary)
+}
@@ -0,0 +1,165 @@
+//! Statistics about the contents of a nix-index database.
+//!
+//! These are useful to monitor the health of an index over time: for example, if a large set of
+//! packages silently drops out of the index after a channel update, the package and file counts
+//! will show a sudden drop.
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use database;
+use files::{FileNode, FileTreeEntry};
+
+error_chain! {
+    links {
+        Database(database::Error, database::ErrorKind);
+    }
+}
+
+/// Totals for a subset of the packages in the index.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Totals {
+    /// The number of packages.
+    pub packages: u64,
+    /// The number of regular files.
+    pub files: u64,
+    /// The number of directories, including the root directories of packages.
+    pub directories: u64,
+    /// The number of symlinks.
+    pub symlinks: u64,
+    /// The number of regular files that are executable.
+    pub executables: u64,
+    /// The total size of all regular files, in bytes.
+    pub bytes: u64,
+}
+
+/// Statistics for a single package.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PackageStats {
+    /// The full store path of the package.
+    pub store_path: String,
+    /// The attribute and output of the package, like `hello.out`.
+    pub attr: String,
+    /// The number of regular files in the package.
+    pub files: u64,
+    /// The total size of the regular files in the package, in bytes.
+    pub bytes: u64,
+}
+
+/// A file name together with the number of times it occurs in the index.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Basename {
+    /// The file name, with invalid UTF-8 replaced by the unicode replacement character.
+    pub name: String,
+    /// The number of entries with this file name, over all packages.
+    pub count: u64,
+}
+
+/// Statistics about a whole database, as computed by `collect`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Stats {
+    /// Totals over all packages.
+    pub total: Totals,
+    /// Totals over the packages that are top-level attributes (see `PathOrigin::toplevel`).
+    pub toplevel: Totals,
+    /// Totals over the packages that were only found as dependencies of top-level attributes.
+    pub dependencies: Totals,
+    /// The largest packages by total file size, largest first.
+    pub largest_packages: Vec<PackageStats>,
+    /// The packages with the most regular files, largest first.
+    pub most_files: Vec<PackageStats>,
+    /// The most common file names (of files, directories and symlinks) with their number of occurrences.
+    pub common_basenames: Vec<Basename>,
+}
+
+impl Totals {
+    fn add(&mut self, other: &Totals) {
+        self.packages += other.packages;
+        self.files += other.files;
+        self.directories += other.directories;
+        self.symlinks += other.symlinks;
+        self.executables += other.executables;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Sorts `items` with the given comparison function and keeps only the first `n` items.
+fn top_by<T, F: FnMut(&T, &T) -> Ordering>(mut items: Vec<T>, n: usize, compare: F) -> Vec<T> {
+    items.sort_by(compare);
+    items.truncate(n);
+    items
+}
+
+/// Computes statistics over all packages produced by `packages`.
+///
+/// The lists of largest packages, packages with the most files and most common basenames
+/// contain at most `top` items each.
+pub fn collect(packages: database::PackageIter, top: usize) -> Result<Stats> {
+    let mut toplevel = Totals::default();
+    let mut dependencies = Totals::default();
+    let mut per_package = Vec::new();
+    let mut basenames: HashMap<Vec<u8>, u64> = HashMap::new();
+
+    for package in packages {
+        let (store_path, entries) = package?;
+
+        let mut totals = Totals {
+            packages: 1,
+            ..Totals::default()
+        };
+        for FileTreeEntry { path, node } in entries {
+            match node {
+                FileNode::Regular { size, executable } => {
+                    totals.files += 1;
+                    totals.bytes += size;
+                    if executable {
+                        totals.executables += 1;
+                    }
+                }
+                FileNode::Directory { .. } => totals.directories += 1,
+                FileNode::Symlink { .. } => totals.symlinks += 1,
+            }
+
+            if let Some(pos) = path.iter().rposition(|c| *c == b'/') {
+                *basenames.entry(path[pos + 1..].to_vec()).or_insert(0) += 1;
+            }
+        }
+
+        let origin = store_path.origin();
+        if origin.toplevel {
+            toplevel.add(&totals);
+        } else {
+            dependencies.add(&totals);
+        }
+
+        per_package.push(PackageStats {
+            store_path: store_path.as_str().into_owned(),
+            attr: format!("{}.{}", origin.attr, origin.output),
+            files: totals.files,
+            bytes: totals.bytes,
+        });
+    }
+
+    let mut total = toplevel.clone();
+    total.add(&dependencies);
+
+    let basenames = basenames
+        .into_iter()
+        .map(|(name, count)| Basename {
+            name: String::from_utf8_lossy(&name).into_owned(),
+            count: count,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Stats {
+        total: total,
+        toplevel: toplevel,
+        dependencies: dependencies,
+        largest_packages: top_by(per_package.clone(), top, |a, b| {
+            b.bytes.cmp(&a.bytes).then_with(|| a.store_path.cmp(&b.store_path))
+        }),
+        most_files: top_by(per_package, top, |a, b| {
+            b.files.cmp(&a.files).then_with(|| a.store_path.cmp(&b.store_path))
+        }),
+        common_basenames: top_by(basenames, top, |a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name))),
+    })
+}
@@ -0,0 +1,342 @@
+//! Limiting the rate and concurrency of requests to binary caches.
+//!
+//! Large binary caches like cache.nixos.org can handle many parallel requests, but small
+//! self-hosted caches quickly become overloaded and start failing requests. This module provides
+//! two ways to be more considerate towards such caches:
+//!
+//! * `RateLimiter` limits the number of requests per second with a token bucket.
+//! * `AdaptiveConcurrency` adjusts the number of parallel requests in AIMD style (additive increase,
+//!   multiplicative decrease): the limit grows slowly while requests succeed quickly, and is halved
+//!   when requests fail or their latency rises well above the lowest latency seen so far.
+//!   Use `adaptive_buffer` to process a stream of futures with this limit.
+//! * `ConcurrencyLimit` is a fixed limit for expensive requests, like downloading NARs.
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use futures::future;
+use futures::stream::{Fuse, FuturesUnordered};
+use futures::unsync::oneshot;
+use futures::{Async, Future, IntoFuture, Poll, Stream};
+
+error_chain! {
+    errors {
+        ParseRate(rate: String) {
+            description("rate parse error")
+            display("invalid rate '{}', expected a positive number of requests per second like 10 or 10/s", rate)
+        }
+    }
+}
+
+/// Converts a duration to seconds.
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+/// Limits the rate of requests with a token bucket.
+///
+/// The bucket holds up to one second worth of tokens, so short bursts of up to `rate` requests
+/// are allowed after a period of inactivity.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    /// The time between two requests at the maximum rate.
+    interval: Duration,
+    /// The time at which the bucket will be full again if no further requests are made.
+    full_at: Cell<Option<Instant>>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter that allows `rate` requests per second on average.
+    ///
+    /// Panics if the rate is not positive.
+    pub fn new(rate: f64) -> RateLimiter {
+        assert!(rate > 0.0, "rate must be positive");
+        RateLimiter {
+            interval: Duration::from_nanos((1e9 / rate) as u64),
+            full_at: Cell::new(None),
+        }
+    }
+
+    /// Takes a token from the bucket and returns how long the caller has to wait before it may
+    /// make its request.
+    pub fn reserve(&self) -> Duration {
+        let now = Instant::now();
+        let capacity = cmp::max(self.interval, Duration::from_secs(1));
+        let full_at = cmp::max(self.full_at.get().unwrap_or(now), now) + self.interval;
+        self.full_at.set(Some(full_at));
+
+        // the request may start as soon as the bucket has room for its token again
+        (full_at - now).checked_sub(capacity).unwrap_or_default()
+    }
+}
+
+impl FromStr for RateLimiter {
+    type Err = Error;
+
+    /// Parses a rate given as requests per second, optionally followed by `/s`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use nix_index::throttle::RateLimiter;
+    ///
+    /// let limiter: RateLimiter = "2/s".parse().unwrap();
+    /// // the first two requests are allowed immediately, then one request every 500ms
+    /// assert_eq!(limiter.reserve(), Duration::from_secs(0));
+    /// assert_eq!(limiter.reserve(), Duration::from_secs(0));
+    /// assert!(limiter.reserve() > Duration::from_millis(400));
+    ///
+    /// assert!("0".parse::<RateLimiter>().is_err());
+    /// assert!("fast".parse::<RateLimiter>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<RateLimiter> {
+        let rate = s.trim().trim_end_matches("/s").parse::<f64>().ok();
+        match rate {
+            Some(rate) if rate > 0.0 && rate.is_finite() => Ok(RateLimiter::new(rate)),
+            _ => Err(ErrorKind::ParseRate(s.to_string()).into()),
+        }
+    }
+}
+
+/// Requests slower than this factor times the lowest latency are a sign of an overloaded server.
+const LATENCY_FACTOR: f64 = 2.0;
+
+/// The factor by which the limit is reduced when the server seems to be overloaded.
+const DECREASE_FACTOR: f64 = 0.5;
+
+/// How fast the baseline latency adapts to higher latencies, so that a single unusually fast
+/// request does not lead to all following requests being considered slow.
+const BASELINE_DRIFT: f64 = 0.01;
+
+/// An adaptive limit for the number of parallel requests.
+///
+/// Report the outcome of each request with `success` or `failure`. The limit starts at the given
+/// initial value and stays between 1 and the given maximum.
+#[derive(Debug)]
+pub struct AdaptiveConcurrency {
+    limit: Cell<f64>,
+    max: f64,
+    /// The lowest latency seen so far, in seconds, drifting slowly towards higher latencies.
+    baseline: Cell<Option<f64>>,
+    /// When the limit was last decreased.
+    last_decrease: Cell<Option<Instant>>,
+}
+
+impl AdaptiveConcurrency {
+    /// Creates a new adaptive limit, starting at `initial` parallel requests with at most `max`.
+    pub fn new(initial: usize, max: usize) -> AdaptiveConcurrency {
+        let max = cmp::max(max, 1) as f64;
+        AdaptiveConcurrency {
+            limit: Cell::new((initial as f64).max(1.0).min(max)),
+            max: max,
+            baseline: Cell::new(None),
+            last_decrease: Cell::new(None),
+        }
+    }
+
+    /// Returns the current limit.
+    pub fn limit(&self) -> usize {
+        self.limit.get() as usize
+    }
+
+    /// Records a request that was answered by the server after the given time.
+    ///
+    /// This increases the limit, unless the latency indicates that the server is overloaded.
+    pub fn success(&self, latency: Duration) {
+        let latency = secs(latency);
+        let baseline = match self.baseline.get() {
+            Some(baseline) if baseline < latency => baseline + (latency - baseline) * BASELINE_DRIFT,
+            _ => latency,
+        };
+        self.baseline.set(Some(baseline));
+
+        if latency > baseline * LATENCY_FACTOR {
+            self.decrease();
+        } else {
+            // increases the limit by about one for each round of `limit` requests
+            let limit = self.limit.get();
+            self.limit.set((limit + 1.0 / limit).min(self.max));
+        }
+    }
+
+    /// Records a request that failed, for example because of a server error or a timeout.
+    pub fn failure(&self) {
+        self.decrease();
+    }
+
+    /// Decreases the limit, but at most once per baseline latency, since all requests that
+    /// were started before the decrease probably see the same overloaded server.
+    fn decrease(&self) {
+        let wait = Duration::from_nanos((self.baseline.get().unwrap_or(0.0) * 1e9) as u64);
+        if self.last_decrease.get().map_or(false, |last| last.elapsed() < wait) {
+            return;
+        }
+
+        self.last_decrease.set(Some(Instant::now()));
+        self.limit.set((self.limit.get() * DECREASE_FACTOR).max(1.0));
+    }
+}
+
+/// A stream that runs the futures produced by another stream in parallel, with an adaptive limit.
+///
+/// This works like `Stream::buffer_unordered`, but the number of futures that run at the same time
+/// is given by an `AdaptiveConcurrency` that can change while the stream is running.
+#[must_use = "streams do nothing unless polled"]
+pub struct AdaptiveBuffer<S>
+where
+    S: Stream,
+    S::Item: IntoFuture,
+{
+    stream: Fuse<S>,
+    running: FuturesUnordered<<S::Item as IntoFuture>::Future>,
+    limit: Rc<AdaptiveConcurrency>,
+}
+
+/// Creates a stream that runs the futures produced by `stream` in parallel, with at most
+/// `limit.limit()` futures running at any time. The results are produced in the order in
+/// which the futures complete.
+pub fn adaptive_buffer<S>(stream: S, limit: Rc<AdaptiveConcurrency>) -> AdaptiveBuffer<S>
+where
+    S: Stream,
+    S::Item: IntoFuture<Error = S::Error>,
+{
+    AdaptiveBuffer {
+        stream: stream.fuse(),
+        running: FuturesUnordered::new(),
+        limit: limit,
+    }
+}
+
+impl<S> Stream for AdaptiveBuffer<S>
+where
+    S: Stream,
+    S::Item: IntoFuture<Error = S::Error>,
+{
+    type Item = <S::Item as IntoFuture>::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // start new futures while we are below the limit
+        while self.running.len() < self.limit.limit() {
+            match self.stream.poll()? {
+                Async::Ready(Some(item)) => self.running.push(item.into_future()),
+                Async::Ready(None) | Async::NotReady => break,
+            }
+        }
+
+        match self.running.poll()? {
+            Async::Ready(Some(item)) => Ok(Async::Ready(Some(item))),
+            Async::Ready(None) if self.stream.is_done() => Ok(Async::Ready(None)),
+            Async::Ready(None) | Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// A fixed limit for the number of operations that run at the same time.
+///
+/// Unlike `AdaptiveConcurrency`, this limit is enforced by the limit itself: `acquire` returns a
+/// future that resolves to a `Permit` as soon as fewer than the maximum number of permits are held.
+/// The permit is released when it is dropped. Waiting futures get their permits in order.
+///
+/// # Example
+///
+/// ```
+/// extern crate futures;
+/// extern crate nix_index;
+/// extern crate tokio_core;
+///
+/// use futures::{future, Future};
+/// use nix_index::throttle::ConcurrencyLimit;
+/// use tokio_core::reactor::Core;
+///
+/// # fn main() {
+/// let mut core = Core::new().unwrap();
+/// let limit = ConcurrencyLimit::new(1);
+///
+/// let first = core.run(limit.acquire()).unwrap();
+/// assert_eq!(limit.available(), 0);
+///
+/// // the second permit is only granted after the first one is released
+/// let mut second = limit.acquire();
+/// let ready = core.run(future::lazy(|| second.poll().map(|p| p.is_ready()))).unwrap();
+/// assert!(!ready);
+/// drop(first);
+/// let second = core.run(second).unwrap();
+/// assert_eq!(limit.available(), 0);
+/// drop(second);
+/// assert_eq!(limit.available(), 1);
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimit {
+    state: Rc<RefCell<LimitState>>,
+}
+
+#[derive(Debug)]
+struct LimitState {
+    /// The number of permits that can be granted right away.
+    available: usize,
+    /// The futures waiting for a permit, in order.
+    waiting: VecDeque<oneshot::Sender<Permit>>,
+}
+
+/// Permission to run an operation, granted by a `ConcurrencyLimit`.
+#[derive(Debug)]
+pub struct Permit {
+    state: Rc<RefCell<LimitState>>,
+}
+
+impl ConcurrencyLimit {
+    /// Creates a limit that allows `max` operations at the same time (at least one).
+    pub fn new(max: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            state: Rc::new(RefCell::new(LimitState {
+                available: cmp::max(max, 1),
+                waiting: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Returns the number of permits that can be granted right away.
+    pub fn available(&self) -> usize {
+        self.state.borrow().available
+    }
+
+    /// Waits until a permit is available and returns it.
+    pub fn acquire(&self) -> Box<dyn Future<Item = Permit, Error = oneshot::Canceled>> {
+        let mut state = self.state.borrow_mut();
+        if state.available > 0 {
+            state.available -= 1;
+            return Box::new(future::ok(Permit { state: self.state.clone() }));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        state.waiting.push_back(sender);
+        Box::new(receiver)
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        // hand the permit over to the first waiting future that is still interested in it
+        loop {
+            let next = self.state.borrow_mut().waiting.pop_front();
+            match next {
+                Some(ref sender) if sender.is_canceled() => continue,
+                Some(sender) => {
+                    // the receiver is still there, so this cannot fail. If it did, dropping the
+                    // returned permit would hand it over to the next waiting future instead.
+                    let _ = sender.send(Permit { state: self.state.clone() });
+                    return;
+                }
+                None => {
+                    self.state.borrow_mut().available += 1;
+                    return;
+                }
+            }
+        }
+    }
+}
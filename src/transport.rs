@@ -0,0 +1,432 @@
+//! Transports that perform the requests of a `hydra::Fetcher`.
+//!
+//! The fetcher itself only decides which URLs to request and how to interpret the responses. The
+//! requests are sent by a `Transport`, which is chosen by the scheme of the URL. This module provides
+//! three transports:
+//!
+//! * `HttpTransport` sends HTTP requests with hyper, optionally through proxies. It is used for
+//!   `http://` and `https://` URLs by default, but it has no TLS support of its own, so `https://`
+//!   URLs can only be requested through a proxy (see `check_url`).
+//! * `FileTransport` reads files from a binary cache in a local directory. It is used for `file://`
+//!   URLs by default.
+//! * `MemoryTransport` answers requests with canned responses, which is useful for testing code that
+//!   uses a fetcher without network access.
+//!
+//! Other transports can be added with `Fetcher::transport`. All transports return HTTP responses, so
+//! that status codes and the `Content-Encoding` header have the same meaning for every transport.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::rc::Rc;
+use std::str;
+use futures::future;
+use futures::Future;
+use hyper::client::{Client as HyperClient, HttpConnector, Request, Response};
+use hyper::header::{Basic, ContentEncoding, Encoding, Headers};
+use hyper::{self, Method, StatusCode, Uri};
+use hyper_proxy::{Custom, Intercept, Proxy, ProxyConnector};
+use tokio_core::reactor::Handle;
+use url::percent_encoding::percent_decode;
+use url::Url;
+
+use proxy::{self, ProxyConfig};
+
+error_chain! {
+    errors {
+        InvalidUrl(url: String) {
+            description("invalid request url")
+            display("the URL '{}' can not be requested", url)
+        }
+        ReadFile(url: String) {
+            description("file read error")
+            display("reading the file '{}' failed", url)
+        }
+        ParseProxy(url: String) {
+            description("proxy config error")
+            display("Can not use the proxy '{}'", url)
+        }
+        HttpsUnsupported(url: String) {
+            description("https without proxy")
+            display("the URL '{}' can not be requested, since https:// is only supported through a proxy; use an http:// URL instead", url)
+        }
+        ProxyTls {
+            description("proxy tls error")
+            display("setting up TLS for requests through the proxy failed")
+        }
+    }
+    foreign_links {
+        Hyper(hyper::Error);
+    }
+}
+
+/// The future returned by `Transport::get`.
+pub type ResponseFuture = Box<dyn Future<Item = Response, Error = Error>>;
+
+/// A way to send GET requests to a binary cache.
+pub trait Transport {
+    /// Sends a GET request with the given headers to the given URL.
+    ///
+    /// The future resolves to the response, which may have any status code. It only fails if there
+    /// is no response at all, for example because the server could not be reached.
+    fn get(&self, url: &str, headers: Headers) -> ResponseFuture;
+
+    /// Returns true if requests made with this transport do not go over the network.
+    ///
+    /// Requests to local transports are not retried, rate limited or cached by the fetcher, and they
+    /// do not count towards its adaptive concurrency limit.
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+/// Checks that the `HttpTransport` with the given proxies can request the given URL.
+///
+/// The `HttpTransport` connects to binary caches without TLS, so `https://` URLs are only supported
+/// if the request is tunneled through a proxy. Other URLs are always accepted.
+///
+/// # Example
+///
+/// ```
+/// use nix_index::proxy::ProxyConfig;
+/// use nix_index::transport::check_url;
+///
+/// let direct = ProxyConfig::default();
+/// assert!(check_url("http://cache.example.org/nix-cache-info", &direct).is_ok());
+/// assert!(check_url("https://cache.example.org/nix-cache-info", &direct).is_err());
+///
+/// let proxy = direct.override_proxy("http://proxy.example.org:3128").unwrap();
+/// assert!(check_url("https://cache.example.org/nix-cache-info", &proxy).is_ok());
+/// ```
+pub fn check_url(url: &str, proxy: &ProxyConfig) -> Result<()> {
+    let uri = match url.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(_) => return Ok(()),
+    };
+    if uri.scheme() == Some("https") && proxy.proxy_for(&uri).is_none() {
+        bail!(ErrorKind::HttpsUnsupported(url.to_string()));
+    }
+    Ok(())
+}
+
+/// The hyper client, with or without a proxy connector.
+enum Client {
+    Proxy(
+        HyperClient<Rc<ProxyConnector<HttpConnector>>>,
+        Rc<ProxyConnector<HttpConnector>>,
+    ),
+    NoProxy(HyperClient<HttpConnector>),
+}
+
+/// A transport that sends HTTP requests, for `http://` and `https://` URLs.
+///
+/// Requests to `https://` URLs fail unless they are tunneled through a proxy (see `check_url`).
+pub struct HttpTransport {
+    client: Client,
+    proxy: ProxyConfig,
+}
+
+impl HttpTransport {
+    /// Creates a new HTTP transport that runs on the given event loop and sends requests through
+    /// the given proxies.
+    pub fn new(handle: &Handle, proxy: &ProxyConfig) -> Result<HttpTransport> {
+        let connector = HttpConnector::new(4, handle);
+        if proxy.is_empty() {
+            let client = hyper::Client::configure()
+                .connector(connector)
+                .build(handle);
+            return Ok(HttpTransport {
+                client: Client::NoProxy(client),
+                proxy: proxy.clone(),
+            });
+        }
+
+        // TLS is only needed for tunneling https:// requests through the proxy
+        let mut proxy_connector = if proxy.proxy_for_scheme("https").is_some() {
+            ProxyConnector::new(connector).chain_err(|| ErrorKind::ProxyTls)?
+        } else {
+            ProxyConnector::unsecured(connector)
+        };
+        for scheme in &["http", "https"] {
+            let url = match proxy.proxy_for_scheme(scheme) {
+                Some(url) => url.clone(),
+                None => continue,
+            };
+
+            let config = proxy.clone();
+            let proxy_url = url.clone();
+            let intercept = Intercept::Custom(Custom::from(move |uri: &Uri| {
+                uri.scheme() == Some(*scheme) && config.proxy_for(uri) == Some(&proxy_url)
+            }));
+
+            let mut without_credentials = url.clone();
+            let _ = without_credentials.set_username("");
+            let _ = without_credentials.set_password(None);
+            let uri = without_credentials
+                .as_str()
+                .parse()
+                .map_err(|_| ErrorKind::ParseProxy(proxy::redact(url.as_str())))?;
+            let mut proxy = Proxy::new(intercept, uri);
+
+            if url.username() != "" {
+                let decode = |s: &str| percent_decode(s.as_bytes()).decode_utf8_lossy().into_owned();
+                proxy.set_authorization(Basic {
+                    username: decode(url.username()),
+                    password: url.password().map(decode),
+                });
+            }
+
+            proxy_connector.add_proxy(proxy);
+        }
+
+        let proxy_connector = Rc::new(proxy_connector);
+        let client = hyper::Client::configure()
+            .connector(proxy_connector.clone())
+            .build(handle);
+        Ok(HttpTransport {
+            client: Client::Proxy(client, proxy_connector),
+            proxy: proxy.clone(),
+        })
+    }
+}
+
+impl Transport for HttpTransport {
+    fn get(&self, url: &str, headers: Headers) -> ResponseFuture {
+        if let Err(e) = check_url(url, &self.proxy) {
+            return Box::new(future::err(e));
+        }
+
+        let uri = match url.parse::<Uri>() {
+            Ok(uri) => uri,
+            Err(e) => {
+                let e = Error::with_chain(hyper::Error::from(e), ErrorKind::InvalidUrl(url.to_string()));
+                return Box::new(future::err(e));
+            }
+        };
+
+        let mut request = Request::new(Method::Get, uri);
+        *request.headers_mut() = headers;
+        let response = match self.client {
+            Client::Proxy(ref client, ref connector) => {
+                if let Some(headers) = connector.http_headers(request.uri()) {
+                    request.headers_mut().extend(headers.iter());
+                    request.set_proxy(true);
+                }
+                client.request(request)
+            }
+            Client::NoProxy(ref client) => client.request(request),
+        };
+        Box::new(response.from_err())
+    }
+}
+
+/// A transport that reads files from a binary cache in a local directory, for `file://` URLs.
+///
+/// Files that do not exist are answered with a 404 response. There are no response headers that
+/// could tell how a file is encoded, so files that are not valid UTF-8 are marked as brotli
+/// compressed. The fetcher recognizes other compression formats by their magic bytes, which take
+/// precedence over the header.
+///
+/// # Example
+///
+/// ```
+/// extern crate nix_index;
+/// extern crate tokio_core;
+///
+/// use std::fs;
+/// use tokio_core::reactor::Core;
+/// use nix_index::hydra::Fetcher;
+/// use nix_index::package::{PathOrigin, StorePath};
+///
+/// # fn main() {
+/// let dir = std::env::temp_dir().join(format!("nix-index-file-transport-{}", std::process::id()));
+/// fs::create_dir_all(&dir).unwrap();
+/// // an uncompressed file listing with a file name that is not ASCII
+/// fs::write(
+///     dir.join("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.ls"),
+///     r#"{"version": 1, "root": {"type": "directory", "entries": {
+///         "größe.txt": {"type": "regular", "size": 10, "executable": false}
+///     }}}"#,
+/// ).unwrap();
+///
+/// let mut core = Core::new().unwrap();
+/// let cache = format!("file://{}", dir.to_str().unwrap());
+/// let fetcher = Fetcher::new(vec![cache.parse().unwrap()], core.handle()).unwrap();
+///
+/// let origin = PathOrigin { attr: "hello".to_string(), output: "out".to_string(), toplevel: true };
+/// let path = StorePath::parse(origin, "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.10").unwrap();
+/// let files = core.run(fetcher.fetch_files(&path)).unwrap().unwrap();
+/// let names = files.to_list().into_iter().map(|f| f.path).collect::<Vec<_>>();
+/// assert!(names.contains(&"/größe.txt".as_bytes().to_vec()));
+///
+/// fs::remove_dir_all(&dir).unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileTransport;
+
+impl FileTransport {
+    /// Reads the file for a `file://` URL. Returns `None` if the file does not exist.
+    fn read(url: &str) -> Result<Option<Vec<u8>>> {
+        let path = Url::parse(url)
+            .ok()
+            .filter(|u| u.scheme() == "file")
+            .and_then(|u| u.to_file_path().ok())
+            .ok_or_else(|| ErrorKind::InvalidUrl(url.to_string()))?;
+
+        let mut contents = Vec::new();
+        match File::open(&path) {
+            Ok(mut file) => file.read_to_end(&mut contents),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => Err(e),
+        }
+        .chain_err(|| ErrorKind::ReadFile(url.to_string()))?;
+        Ok(Some(contents))
+    }
+}
+
+impl Transport for FileTransport {
+    fn get(&self, url: &str, _headers: Headers) -> ResponseFuture {
+        let response = FileTransport::read(url).map(|contents| match contents {
+            None => Response::new().with_status(StatusCode::NotFound),
+            Some(contents) => {
+                let mut response = Response::new();
+                if str::from_utf8(&contents).is_err() {
+                    response.headers_mut().set(ContentEncoding(vec![Encoding::Brotli]));
+                }
+                response.with_body(contents)
+            }
+        });
+        Box::new(future::result(response))
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+/// A canned response of a `MemoryTransport`.
+#[derive(Debug, Clone)]
+struct CannedResponse {
+    status: StatusCode,
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+/// A transport that answers requests with canned responses.
+///
+/// Requests for URLs without a response are answered with a 404 response. The transport records
+/// the URLs of all requests, which can be inspected with `requests`.
+///
+/// # Example
+///
+/// ```
+/// extern crate nix_index;
+/// extern crate tokio_core;
+///
+/// use std::rc::Rc;
+/// use tokio_core::reactor::Core;
+/// use nix_index::hydra::Fetcher;
+/// use nix_index::package::{PathOrigin, StorePath};
+/// use nix_index::transport::MemoryTransport;
+///
+/// # fn main() {
+/// let mut transport = MemoryTransport::new();
+/// transport.insert(
+///     "mem://cache/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.narinfo",
+///     b"StorePath: /nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.10
+/// URL: nar/hello.nar.xz
+/// Compression: xz
+/// NarHash: sha256:0000000000000000000000000000000000000000000000000000
+/// NarSize: 100
+/// References: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.10 bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-glibc-2.27
+/// ".to_vec(),
+/// );
+/// transport.insert(
+///     "mem://cache/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.ls",
+///     br#"{"version": 1, "root": {"type": "directory", "entries": {
+///         "bin": {"type": "directory", "entries": {
+///             "hello": {"type": "regular", "size": 1024, "executable": true}
+///         }}
+///     }}}"#.to_vec(),
+/// );
+/// let transport = Rc::new(transport);
+///
+/// let mut core = Core::new().unwrap();
+/// let fetcher = Fetcher::new(vec!["mem://cache".parse().unwrap()], core.handle())
+///     .unwrap()
+///     .transport("mem", transport.clone());
+///
+/// let origin = PathOrigin { attr: "hello".to_string(), output: "out".to_string(), toplevel: true };
+/// let path = StorePath::parse(origin, "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.10").unwrap();
+///
+/// let (path, references) = core.run(fetcher.fetch_references(path)).unwrap();
+/// assert_eq!(path.name(), "hello-2.10");
+/// let references = references.unwrap();
+/// assert_eq!(references.len(), 2);
+/// assert_eq!(references[1].name(), "glibc-2.27");
+///
+/// let files = core.run(fetcher.fetch_files(&path)).unwrap().unwrap();
+/// let names = files.to_list().into_iter().map(|f| f.path).collect::<Vec<_>>();
+/// assert!(names.contains(&b"/bin/hello".to_vec()));
+///
+/// // the glibc narinfo is missing, so there are no references
+/// let glibc = references[1].clone();
+/// assert_eq!(core.run(fetcher.fetch_references(glibc)).unwrap().1, None);
+///
+/// assert_eq!(transport.requests(), vec![
+///     "mem://cache/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.narinfo",
+///     "mem://cache/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.ls",
+///     "mem://cache/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb.narinfo",
+/// ]);
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct MemoryTransport {
+    responses: HashMap<String, CannedResponse>,
+    requests: RefCell<Vec<String>>,
+}
+
+impl MemoryTransport {
+    /// Creates a transport without any responses.
+    pub fn new() -> MemoryTransport {
+        MemoryTransport::default()
+    }
+
+    /// Answers requests for the given URL with a successful response with the given body.
+    ///
+    /// The body is sent without a `Content-Encoding` header. The fetcher still recognizes xz, zstd
+    /// and bzip2 compressed bodies by their magic bytes.
+    pub fn insert<S: Into<String>>(&mut self, url: S, body: Vec<u8>) {
+        self.insert_response(url, StatusCode::Ok, Headers::new(), body);
+    }
+
+    /// Answers requests for the given URL with a response with the given status, headers and body.
+    pub fn insert_response<S: Into<String>>(&mut self, url: S, status: StatusCode, headers: Headers, body: Vec<u8>) {
+        let response = CannedResponse {
+            status: status,
+            headers: headers,
+            body: body,
+        };
+        self.responses.insert(url.into(), response);
+    }
+
+    /// Returns the URLs of all requests made so far, in order.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.borrow().clone()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn get(&self, url: &str, _headers: Headers) -> ResponseFuture {
+        self.requests.borrow_mut().push(url.to_string());
+        let response = match self.responses.get(url) {
+            Some(canned) => Response::new()
+                .with_status(canned.status)
+                .with_headers(canned.headers.clone())
+                .with_body(canned.body.clone()),
+            None => Response::new().with_status(StatusCode::NotFound),
+        };
+        Box::new(future::ok(response))
+    }
+}
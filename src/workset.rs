@@ -171,6 +171,60 @@ impl<K: Hash + Eq + 'static, V: 'static> WorkSet<K, V> {
     }
 }
 
+/// The state of a work set at some point in time.
+///
+/// Snapshots can be used to persist the state of a long-running computation and to
+/// continue it later with `WorkSet::from_snapshot`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot<K: Hash + Eq, V> {
+    /// The keys of all items that have been taken from the queue for processing.
+    pub seen: HashSet<K>,
+    /// The items that still need processing. Restoring the snapshot preserves their order.
+    pub queue: Vec<(K, V)>,
+}
+
+/// Takes snapshots of the state of a `WorkSet` (see `WorkSet::snapshots`).
+///
+/// Like a `WorkSetWatch`, this does not prevent the queue from terminating.
+pub struct WorkSetSnapshots<K, V> {
+    state: Weak<RefCell<Shared<K, V>>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> WorkSetSnapshots<K, V> {
+    /// Returns the current state of the work set, or `None` if the queue has already terminated.
+    ///
+    /// Note that items which have been produced by the stream but are still being processed
+    /// are already part of the seen set, so they will not be produced again by a work set
+    /// that is restored from the snapshot.
+    pub fn take(&self) -> Option<Snapshot<K, V>> {
+        self.state.upgrade().map(|shared| {
+            let shared = shared.borrow();
+            Snapshot {
+                seen: shared.seen.clone(),
+                queue: shared.queue.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            }
+        })
+    }
+}
+
+impl<K: Hash + Eq, V> WorkSet<K, V> {
+    /// Constructs a work set that continues from the given snapshot.
+    ///
+    /// Items whose key is part of the seen set of the snapshot are never added again.
+    pub fn from_snapshot(snapshot: Snapshot<K, V>) -> WorkSet<K, V> {
+        let shared = Shared {
+            seen: snapshot.seen,
+            queue: OrderMap::from_iter(snapshot.queue),
+        };
+        WorkSet { state: Rc::new(RefCell::new(shared)) }
+    }
+
+    /// Returns a handle that can take snapshots of the state of this work set.
+    pub fn snapshots(&self) -> WorkSetSnapshots<K, V> {
+        WorkSetSnapshots { state: Rc::downgrade(&self.state) }
+    }
+}
+
 /// Constructs a new work set with the given initial work items.
 impl<K: Hash + Eq + 'static, V: 'static> FromIterator<(K, V)> for WorkSet<K, V> {
     fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> WorkSet<K, V> {